@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// Name of the optional rules file inside a target directory
+pub const ALIAS_FILE: &str = ".lajittelia";
+
+/// Rules read from the `.lajittelia` file of a target directory:
+///
+/// ```toml
+/// aliases = ["ac dc", "acdc"]
+/// exclude = ["ac dc tribute"]
+/// regex = ['ac ?dc live \d{4}']
+/// glob = ["acdc-*-live"]
+/// extensions = ["mp3", "flac"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirRules {
+    // Extra aliases, which may contain "," and characters not allowed in file names
+    pub aliases: Vec<String>,
+    // Negative aliases, names containing any of these are not sorted into the directory
+    pub exclude: Vec<String>,
+    // Regular expressions matched against the normalized name, or the name
+    // before lowercasing if the normalized name does not match
+    pub regex: Vec<String>,
+    // Glob patterns matched against the whole name without extension
+    pub glob: Vec<String>,
+    // If not empty, only files with these extensions are sorted into the directory
+    pub extensions: Vec<String>,
+}
+
+impl DirRules {
+    /// Read rules file, `Ok(None)` if it doesn't exist
+    pub fn load(dir: &Path) -> Result<Option<DirRules>, Error> {
+        let path = dir.join(ALIAS_FILE);
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let data = fs::read_to_string(&path)?;
+
+        let rules: DirRules = toml::from_str(&data).map_err(|e| Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        ))?;
+
+        rules.validate(dir)?;
+
+        Ok(Some(rules))
+    }
+
+    /// Check that regex and glob rules of target directory `dir` compile
+    pub fn validate(&self, dir: &Path) -> Result<(), Error> {
+        let invalid = |kind: &str, pattern: &str, e: String| Error::new(
+            ErrorKind::InvalidInput,
+            format!("target directory {}: invalid {} alias {:?}: {}", dir.display(), kind, pattern, e),
+        );
+
+        for pattern in &self.regex {
+            regex_rule(pattern).map_err(|e| invalid("regex", pattern, e))?;
+        }
+
+        for pattern in &self.glob {
+            glob_rule(pattern).map_err(|e| invalid("glob", pattern, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Does the extension filter accept `extension`, `None` for directories
+    pub fn accepts_extension(&self, extension: Option<&str>) -> bool {
+        match extension {
+            Some(ext) if !self.extensions.is_empty() => {
+                self.extensions.iter()
+                    .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+            }
+            _ => true,
+        }
+    }
+}
+
+// Regex alias, case insensitive
+pub(crate) fn regex_rule(pattern: &str) -> Result<Regex, String> {
+    if pattern.is_empty() {
+        return Err("empty pattern".to_string());
+    }
+
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+// Glob alias, case insensitive
+pub(crate) fn glob_rule(pattern: &str) -> Result<GlobMatcher, String> {
+    if pattern.is_empty() {
+        return Err("empty pattern".to_string());
+    }
+
+    GlobBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map(|g| g.compile_matcher())
+        .map_err(|e| e.to_string())
+}
+
+// Does `dir` contain directories other than hidden ones
+fn has_subdirectories(dir: &Path) -> Result<bool, Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.path().is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Alias as it was written, see `AliasIndex::origins`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasOrigin {
+    // Not trimmed nor lowercased, may be empty ("Foo," has "Foo" and "")
+    pub raw: String,
+    pub dir: PathBuf,
+    // From the `.lajittelia` file instead of the directory name
+    pub from_rules: bool,
+}
+
+/// Lowercased aliases pointing to the target directories they sort into
+#[derive(Debug, Clone, Default)]
+pub struct AliasIndex {
+    // Directory the aliases were generated from
+    root: Option<PathBuf>,
+    entries: HashMap<String, PathBuf>,
+    // Target directory specific rules
+    rules: BTreeMap<PathBuf, DirRules>,
+    // Every alias read from the target, including duplicates and empty ones
+    origins: Vec<AliasOrigin>,
+}
+
+impl AliasIndex {
+    pub fn new() -> AliasIndex {
+        AliasIndex::default()
+    }
+
+    /// Generate aliases from the directory names found in `target`.
+    /// Name "Quux, Xyzzy" generates aliases "quux" and "xyzzy".
+    /// Rules from `.lajittelia` files (see `DirRules`) are added as well.
+    pub fn from_target(target: &Path) -> Result<AliasIndex, Error> {
+        AliasIndex::from_target_nested(target, Some(0))
+    }
+
+    /// Like `from_target`, but directories with subdirectories are descended
+    /// into, `max_depth` levels below the direct children of `target` (None
+    /// for no limit). Only leaf directories ("Music/Rock/Foo") get aliases
+    /// from their names, intermediate directories ("Music/Rock") may add
+    /// aliases and constraints for everything below them with `.lajittelia`.
+    pub fn from_target_nested(target: &Path, max_depth: Option<usize>) -> Result<AliasIndex, Error> {
+        if !target.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("not a directory: {}", target.display()),
+            ));
+        }
+
+        let mut index = AliasIndex {
+            root: Some(target.to_path_buf()),
+            ..AliasIndex::default()
+        };
+
+        index.add_children(target, 0, max_depth)?;
+
+        Ok(index)
+    }
+
+    fn add_children(&mut self, dir: &Path, depth: usize, max_depth: Option<usize>) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if !path.is_dir() {
+                continue;
+            }
+
+            // Hidden directories inside nested targets are not targets
+            if depth > 0 && entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            // Symbolic links are not descended into
+            let descend = max_depth.is_none_or(|max| depth < max)
+                && !entry.file_type()?.is_symlink()
+                && has_subdirectories(&path)?;
+
+            if descend {
+                self.add_children(&path, depth + 1, max_depth)?;
+            } else {
+                self.insert_dir_name(&path);
+            }
+
+            if let Some(rules) = DirRules::load(&path)? {
+                self.insert_rules(path, rules)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Aliases from the directory name, names which are not valid UTF-8
+    // are used lossily
+    fn insert_dir_name(&mut self, dir: &Path) {
+        let name = match dir.file_name() {
+            Some(n) => n.to_string_lossy(),
+            None => return,
+        };
+
+        // Composed form, so that names created on macOS give the same aliases
+        let name: String = name.nfc().collect();
+
+        // Split with ",", or use whole directory name
+        for raw in name.split(',') {
+            self.insert_origin(raw, dir, false);
+        }
+    }
+
+    // Remember where the alias came from and add it, empty aliases would
+    // match every name and are only remembered
+    fn insert_origin(&mut self, raw: &str, dir: &Path, from_rules: bool) {
+        self.origins.push(AliasOrigin {
+            raw: raw.to_string(),
+            dir: dir.to_path_buf(),
+            from_rules,
+        });
+
+        let alias = raw.trim().to_lowercase();
+
+        if !alias.is_empty() {
+            self.insert(alias, dir.to_path_buf());
+        }
+    }
+
+    /// Add rules of target directory `dir`, extra aliases are added to the index.
+    /// Fails if a regex or glob rule is invalid.
+    pub fn insert_rules(&mut self, dir: PathBuf, rules: DirRules) -> Result<(), Error> {
+        rules.validate(&dir)?;
+
+        for alias in &rules.aliases {
+            self.insert_origin(alias, &dir, true);
+        }
+
+        self.rules.entry(dir)
+            .and_modify(|r| {
+                r.aliases.extend(rules.aliases.iter().cloned());
+                r.exclude.extend(rules.exclude.iter().cloned());
+                r.regex.extend(rules.regex.iter().cloned());
+                r.glob.extend(rules.glob.iter().cloned());
+                r.extensions.extend(rules.extensions.iter().cloned());
+            })
+            .or_insert(rules);
+
+        Ok(())
+    }
+
+    pub fn rules(&self, dir: &Path) -> Option<&DirRules> {
+        self.rules.get(dir)
+    }
+
+    /// Target directories with rules
+    pub fn iter_rules(&self) -> impl Iterator<Item=(&Path, &DirRules)> {
+        self.rules.iter()
+            .map(|(d, r)| (d.as_path(), r))
+    }
+
+    /// Add alias, returns the directory it previously pointed to
+    pub fn insert<S: Into<String>>(&mut self, alias: S, dir: PathBuf) -> Option<PathBuf> {
+        self.entries.insert(alias.into(), dir)
+    }
+
+    /// Aliases as they were read from the target, in reading order. Unlike
+    /// `iter`, aliases given to more than one directory and empty aliases
+    /// are included.
+    pub fn origins(&self) -> &[AliasOrigin] {
+        &self.origins
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Target root and all target directories
+    pub fn directories(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self.root.iter()
+            .chain(self.entries.values())
+            .chain(self.rules.keys())
+            .cloned()
+            .collect();
+
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&Path> {
+        self.entries.get(alias).map(|p| p.as_path())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &Path)> {
+        self.entries.iter()
+            .map(|(a, p)| (a.as_str(), p.as_path()))
+    }
+
+    /// Aliases sorted by length, longest first
+    pub fn sorted_aliases(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.entries.keys()
+            .map(|x|
+                x.to_string()
+            ).collect();
+
+        keys.sort_by(|a, b|
+            b.len().cmp(&a.len()).then_with(|| a.cmp(b))
+        );
+
+        keys
+    }
+}
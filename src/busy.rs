@@ -0,0 +1,343 @@
+// Files which may still be written, by a browser or a torrent client for example
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::scan::{SkipReason, Skipped};
+
+/// Checks for files still being written or downloaded. Directories are
+/// checked by the files inside them.
+#[derive(Debug, Clone)]
+pub struct BusyOptions {
+    // File name patterns of partial downloads, case insensitive globs
+    pub partial_patterns: Vec<String>,
+    // Files modified less than this long ago are skipped
+    pub min_age: Option<Duration>,
+    // Size and modification time are compared again after this long and
+    // files which changed are skipped
+    pub stable_check: Option<Duration>,
+    // Skip files held open for writing by some process, from /proc/*/fd
+    // (Linux only, processes of other users are not visible)
+    pub check_open: bool,
+}
+
+impl Default for BusyOptions {
+    fn default() -> Self {
+        BusyOptions {
+            partial_patterns: ["*.part", "*.crdownload", "*.!qB", "*.partial", "*.download"]
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            min_age: None,
+            stable_check: None,
+            check_open: false,
+        }
+    }
+}
+
+impl BusyOptions {
+    /// Partial download patterns must be valid globs
+    pub fn validate(&self) -> Result<(), Error> {
+        partial_set(&self.partial_patterns).map(|_| ())
+    }
+}
+
+fn partial_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut set = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid partial download pattern {:?}: {}", pattern, e),
+            ))?;
+
+        set.add(glob);
+    }
+
+    set.build().map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+// `path` itself, or files and directories inside it, symlinks not followed
+pub(crate) fn all_paths(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_path_buf()];
+    let mut i = 0;
+
+    while i < paths.len() {
+        let is_dir = paths[i].symlink_metadata().is_ok_and(|m| m.is_dir());
+
+        if is_dir {
+            if let Ok(entries) = fs::read_dir(&paths[i]) {
+                paths.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+            }
+        }
+
+        i += 1;
+    }
+
+    paths
+}
+
+// Total size and newest modification time
+pub(crate) fn snapshot(paths: &[PathBuf]) -> (u64, Option<SystemTime>) {
+    paths.iter()
+        .filter_map(|p| p.symlink_metadata().ok())
+        .fold((0, None), |(size, newest), m| {
+            let modified = m.modified().ok();
+            (size + m.len(), newest.max(modified))
+        })
+}
+
+// Canonical paths of files open for writing by any visible process
+#[cfg(target_os = "linux")]
+fn open_for_writing() -> HashSet<PathBuf> {
+    let mut open = HashSet::new();
+
+    let processes = match fs::read_dir("/proc") {
+        Ok(p) => p,
+        Err(_) => return open,
+    };
+
+    for process in processes.filter_map(|p| p.ok()) {
+        let pid = process.file_name();
+
+        if !pid.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+
+        // Permission denied for processes of other users
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+
+        for fd in fds.filter_map(|f| f.ok()) {
+            let target = match fs::read_link(fd.path()) {
+                Ok(t) if t.is_absolute() => t,
+                _ => continue,
+            };
+
+            // "flags:\t0100001", access mode is the lowest two bits
+            let info = process.path().join("fdinfo").join(fd.file_name());
+            let flags = fs::read_to_string(info).ok().and_then(|info| {
+                info.lines()
+                    .find_map(|l| l.strip_prefix("flags:"))
+                    .and_then(|f| u32::from_str_radix(f.trim(), 8).ok())
+            });
+
+            if flags.is_some_and(|f| f & 0o3 != 0) {
+                open.insert(target);
+            }
+        }
+    }
+
+    open
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_for_writing() -> HashSet<PathBuf> {
+    HashSet::new()
+}
+
+/// Split sorted `candidates` to ones which can be sorted and ones which may
+/// still be written. Contents of skipped directories are left out as well.
+/// Sleeps for `BusyOptions::stable_check` if it is set.
+pub fn check_busy(candidates: Vec<PathBuf>, options: &BusyOptions) -> Result<(Vec<PathBuf>, Vec<Skipped>), Error> {
+    let partial = partial_set(&options.partial_patterns)?;
+    let open = if options.check_open { open_for_writing() } else { HashSet::new() };
+    let now = SystemTime::now();
+
+    let mut ready: Vec<(PathBuf, Vec<PathBuf>)> = Vec::new();
+    let mut skipped: Vec<Skipped> = Vec::new();
+
+    let skip = |skipped: &mut Vec<Skipped>, path: PathBuf, reason: SkipReason| {
+        skipped.push(Skipped { path, reason });
+    };
+
+    for path in candidates {
+        if skipped.iter().any(|s| path.starts_with(&s.path)) {
+            continue;
+        }
+
+        let paths = all_paths(&path);
+
+        let is_partial = paths.iter()
+            .filter_map(|p| p.file_name())
+            .any(|n| partial.is_match(n));
+
+        if is_partial {
+            skip(&mut skipped, path, SkipReason::PartialDownload);
+            continue;
+        }
+
+        let is_open = !open.is_empty() && paths.iter()
+            .filter_map(|p| fs::canonicalize(p).ok())
+            .any(|p| open.contains(&p));
+
+        if is_open {
+            skip(&mut skipped, path, SkipReason::OpenForWriting);
+            continue;
+        }
+
+        if let Some(min_age) = options.min_age {
+            let newest = snapshot(&paths).1;
+
+            // Modification times in the future count as recent
+            if newest.is_some_and(|m| now.duration_since(m).map_or(true, |age| age < min_age)) {
+                skip(&mut skipped, path, SkipReason::RecentlyModified);
+                continue;
+            }
+        }
+
+        ready.push((path, paths));
+    }
+
+    let interval = match options.stable_check {
+        Some(i) => i,
+        None => return Ok((ready.into_iter().map(|(path, _)| path).collect(), skipped)),
+    };
+
+    let before: Vec<_> = ready.iter().map(|(_, paths)| snapshot(paths)).collect();
+
+    sleep(interval);
+
+    let mut stable = Vec::new();
+
+    for ((path, _), before) in ready.into_iter().zip(before) {
+        if skipped.iter().any(|s| path.starts_with(&s.path)) {
+            continue;
+        }
+
+        // Files added to a directory change the snapshot as well
+        if snapshot(&all_paths(&path)) != before {
+            skip(&mut skipped, path, SkipReason::Changing);
+            continue;
+        }
+
+        stable.push(path);
+    }
+
+    Ok((stable, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testdir::temp_dir;
+
+    fn reasons(skipped: &[Skipped]) -> Vec<(PathBuf, SkipReason)> {
+        skipped.iter().map(|s| (s.path.clone(), s.reason.clone())).collect()
+    }
+
+    // Modification time `age` ago
+    fn set_age(path: &Path, age: Duration) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn partial_downloads_are_skipped_with_their_directory() {
+        let dir = temp_dir("busy-partial");
+        fs::create_dir_all(dir.join("album")).unwrap();
+        fs::write(dir.join("album").join("track.mp3.PART"), "").unwrap();
+        fs::write(dir.join("album").join("cover.jpg"), "").unwrap();
+        fs::write(dir.join("song.mp3"), "").unwrap();
+
+        let candidates = vec![dir.join("album"), dir.join("album").join("cover.jpg"), dir.join("song.mp3")];
+        let (ready, skipped) = check_busy(candidates, &BusyOptions::default()).unwrap();
+
+        assert_eq!(ready, vec![dir.join("song.mp3")]);
+        assert_eq!(reasons(&skipped), vec![(dir.join("album"), SkipReason::PartialDownload)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recently_modified_files_are_skipped() {
+        let dir = temp_dir("busy-recent");
+        for name in ["old.mp3", "new.mp3", "future.mp3"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        set_age(&dir.join("old.mp3"), Duration::from_secs(600));
+        let future = fs::File::options().write(true).open(dir.join("future.mp3")).unwrap();
+        future.set_modified(SystemTime::now() + Duration::from_secs(600)).unwrap();
+
+        let options = BusyOptions {
+            min_age: Some(Duration::from_secs(60)),
+            ..BusyOptions::default()
+        };
+        let candidates = vec![dir.join("future.mp3"), dir.join("new.mp3"), dir.join("old.mp3")];
+        let (ready, skipped) = check_busy(candidates, &options).unwrap();
+
+        assert_eq!(ready, vec![dir.join("old.mp3")]);
+        assert_eq!(reasons(&skipped), vec![
+            (dir.join("future.mp3"), SkipReason::RecentlyModified),
+            (dir.join("new.mp3"), SkipReason::RecentlyModified),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changing_files_are_skipped() {
+        let dir = temp_dir("busy-changing");
+        fs::create_dir_all(dir.join("album")).unwrap();
+        fs::write(dir.join("album").join("track.mp3"), "").unwrap();
+        fs::write(dir.join("song.mp3"), "").unwrap();
+
+        // Written to during the stable check
+        let added = dir.join("album").join("added.mp3");
+        let writer = std::thread::spawn(move || {
+            sleep(Duration::from_millis(50));
+            fs::write(added, "more").unwrap();
+        });
+
+        let options = BusyOptions {
+            stable_check: Some(Duration::from_millis(300)),
+            ..BusyOptions::default()
+        };
+        let (ready, skipped) = check_busy(vec![dir.join("album"), dir.join("song.mp3")], &options).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(ready, vec![dir.join("song.mp3")]);
+        assert_eq!(reasons(&skipped), vec![(dir.join("album"), SkipReason::Changing)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn files_open_for_writing_are_skipped() {
+        let dir = temp_dir("busy-open");
+        fs::write(dir.join("reading.mp3"), "").unwrap();
+        let _writing = fs::File::create(dir.join("writing.mp3")).unwrap();
+        let _reading = fs::File::open(dir.join("reading.mp3")).unwrap();
+
+        let options = BusyOptions {
+            check_open: true,
+            ..BusyOptions::default()
+        };
+        let (ready, skipped) = check_busy(vec![dir.join("reading.mp3"), dir.join("writing.mp3")], &options).unwrap();
+
+        assert_eq!(ready, vec![dir.join("reading.mp3")]);
+        assert_eq!(reasons(&skipped), vec![(dir.join("writing.mp3"), SkipReason::OpenForWriting)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_partial_pattern_fails() {
+        let options = BusyOptions {
+            partial_patterns: vec!["*.{part".to_string()],
+            ..BusyOptions::default()
+        };
+
+        assert_eq!(options.validate().unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(check_busy(vec![], &options).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Named set of options for one sorting job. Options given on the command
+/// line override the profile, unset ones fall back to defaults.
+///
+/// ```toml
+/// [profiles.music]
+/// target = "~/Music"
+/// sources = ["~/Downloads"]
+/// recursive = true
+/// nested = true
+/// multiple = "longest"
+/// collision = "delete-identical"
+/// extensions = ["mp3", "flac"]
+/// exclude = ["Thumbs.db", ".DS_Store"]
+/// fuzzy = true
+/// fold_accents = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub target: Option<PathBuf>,
+    pub sources: Vec<PathBuf>,
+    pub recursive: bool,
+    pub max_depth: Option<usize>,
+    pub one_file_system: bool,
+    pub directories: bool,
+    // Source file filters, see `FileFilter`. Lists given on the command
+    // line replace the profile's.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub extensions: Vec<String>,
+    pub exclude_extensions: Vec<String>,
+    // Files still being written, in seconds, see `BusyOptions`
+    pub min_age: Option<u64>,
+    pub stable_check: Option<u64>,
+    // Added to the default partial download patterns
+    pub partial: Vec<String>,
+    pub check_open: bool,
+    pub nested: bool,
+    pub target_depth: Option<usize>,
+    // Multiple match policy name, as with --multiple
+    pub multiple: Option<String>,
+    // Target directory names for the priority policy
+    pub priority: Vec<PathBuf>,
+    pub ambiguous_dir: Option<PathBuf>,
+    // Collision policy name, as with --collision
+    pub collision: Option<String>,
+    // Rename suffix format, as with --suffix
+    pub suffix: Option<String>,
+    // Added to the default compound extensions
+    pub compound_extensions: Vec<String>,
+    pub fold_accents: bool,
+    pub fuzzy: bool,
+    pub fuzzy_threshold: Option<u8>,
+    pub review_below: Option<u8>,
+    pub journal: Option<PathBuf>,
+    // Output format name, as with --output-format
+    pub output_format: Option<String>,
+}
+
+/// Contents of the configuration file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Default configuration file: `$XDG_CONFIG_HOME/lajittelia/config.toml`
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => match env::var_os("HOME") {
+            Some(h) => PathBuf::from(h).join(".config"),
+            None => PathBuf::from(env::var_os("APPDATA")?),
+        },
+    };
+
+    Some(config_dir.join("lajittelia").join("config.toml"))
+}
+
+// "~/foo" -> "$HOME/foo"
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Profile {
+    // Expand "~" in paths
+    fn expand(&mut self) {
+        for p in self.target.iter_mut()
+            .chain(self.sources.iter_mut())
+            .chain(self.ambiguous_dir.iter_mut())
+            .chain(self.journal.iter_mut()) {
+            *p = expand_home(p);
+        }
+    }
+}
+
+impl Config {
+    /// Read configuration file, "~" in profile paths is expanded to the home directory
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        let mut config: Config = toml::from_str(&data).map_err(|e| Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        ))?;
+
+        for profile in config.profiles.values_mut() {
+            profile.expand();
+        }
+
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+}
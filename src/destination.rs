@@ -0,0 +1,447 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::bytes::Regex;
+use regex::escape;
+
+use crate::os_name::{name_bytes, name_from_bytes};
+
+/// How colliding destinations are renamed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameOptions {
+    // Added after the name (before the extension). "{n}" is replaced with
+    // a counter starting from 1 and "{timestamp}" with the current UTC time
+    // "YYYYMMDD-HHMMSS". Examples: " ({n})", "_{n}", " - copy {n}", "_{timestamp}".
+    pub suffix: String,
+    // Extensions kept together, without the leading dot: "tar.gz" gives
+    // "archive (1).tar.gz" instead of "archive.tar (1).gz"
+    pub compound_extensions: Vec<String>,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        RenameOptions {
+            suffix: " ({n})".to_string(),
+            compound_extensions: ["tar.gz", "tar.bz2", "tar.xz", "tar.zst"]
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl RenameOptions {
+    /// Suffix must contain "{n}" or "{timestamp}" and no path separators
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |why: &str| Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid suffix format {:?}: {}", self.suffix, why),
+        ));
+
+        if !self.suffix.contains("{n}") && !self.suffix.contains("{timestamp}") {
+            return invalid("must contain {n} or {timestamp}");
+        }
+
+        if self.suffix.contains('/') || self.suffix.contains(std::path::MAIN_SEPARATOR) {
+            return invalid("must not contain path separators");
+        }
+
+        Ok(())
+    }
+}
+
+/// File name and path length limits in bytes of the file system a
+/// directory is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameLimits {
+    // NAME_MAX
+    pub name_max: usize,
+    // PATH_MAX, including the terminating NUL
+    pub path_max: usize,
+}
+
+impl Default for NameLimits {
+    fn default() -> Self {
+        NameLimits {
+            name_max: 255,
+            path_max: 4096,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn pathconf(dir: &Path, name: libc::c_int) -> Option<usize> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+    // -1 means no limit or an error, both give the default
+    let value = unsafe { libc::pathconf(path.as_ptr(), name) };
+    usize::try_from(value).ok().filter(|v| *v > 0)
+}
+
+impl NameLimits {
+    /// Query limits with `pathconf`, defaults are used for unknown limits
+    #[cfg(unix)]
+    pub fn of(dir: &Path) -> NameLimits {
+        let defaults = NameLimits::default();
+
+        NameLimits {
+            name_max: pathconf(dir, libc::_PC_NAME_MAX).unwrap_or(defaults.name_max),
+            path_max: pathconf(dir, libc::_PC_PATH_MAX).unwrap_or(defaults.path_max),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_dir: &Path) -> NameLimits {
+        NameLimits::default()
+    }
+}
+
+// Longest prefix of `bytes` at most `max` bytes long which doesn't split a
+// UTF-8 character
+fn truncate_utf8(bytes: &[u8], max: usize) -> &[u8] {
+    if bytes.len() <= max {
+        return bytes;
+    }
+
+    let mut end = max;
+
+    // Continuation bytes are 0b10xxxxxx
+    while end > 0 && bytes[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+
+    &bytes[..end]
+}
+
+// Split name to stem and extension (with the leading dot). Dotfiles
+// (".bashrc") and names without a dot have no extension, directories are
+// never split.
+fn split_name<'a>(name: &'a [u8], is_dir: bool, compound: &[String]) -> (&'a [u8], &'a [u8]) {
+    if is_dir {
+        return (name, &[]);
+    }
+
+    let lower = name.to_ascii_lowercase();
+
+    for ext in compound {
+        let ext = format!(".{}", ext.trim_start_matches('.').to_ascii_lowercase());
+
+        // Something other than dots has to be left for the stem
+        if lower.ends_with(ext.as_bytes()) {
+            let at = name.len() - ext.len();
+
+            if name[..at].iter().any(|b| *b != b'.') {
+                return (&name[..at], &name[at..]);
+            }
+        }
+    }
+
+    match name.iter().rposition(|b| *b == b'.') {
+        Some(0) | None => (name, &[]),
+        Some(at) => (&name[..at], &name[at..]),
+    }
+}
+
+// Current UTC time "YYYYMMDD-HHMMSS"
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since epoch, see https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+// Regex for an existing counter suffix at the end of a stem, so that
+// "song (1)" collides to "song (2)" and not "song (1) (1)"
+fn counter_regex(suffix: &str) -> Option<Regex> {
+    if !suffix.contains("{n}") || suffix.contains("{timestamp}") {
+        return None;
+    }
+
+    let pattern = escape(suffix).replace(r"\{n\}", r"(\d+)");
+    Regex::new(&format!("^(?s)((?-u:.)+?){}$", pattern)).ok()
+}
+
+/// Destination for `source_path` in `target_dir`. If the name is taken by
+/// an existing file or directory or by another file in the same plan
+/// (`reserved`), a suffix is added, see `RenameOptions`. Names too long for
+/// the target file system (see `NameLimits`) get their stem truncated,
+/// `ErrorKind::InvalidFilename` is returned if the name can't be made to fit.
+pub fn rename_destination(
+    source_path: &Path, // What we're moving
+    target_dir: &Path, // To where
+    reserved: &HashSet<PathBuf>, // Destinations already taken by the plan
+    options: &RenameOptions,
+) -> Result<PathBuf, Error> {
+    if !target_dir.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("not a directory: {}", target_dir.display()),
+        ));
+    }
+
+    let file_name = match source_path.file_name() {
+        Some(n) => n,
+        None => return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no file name: {}", source_path.display()),
+        )),
+    };
+
+    let taken = |p: &Path| p.symlink_metadata().is_ok() || reserved.contains(p);
+    let limits = NameLimits::of(target_dir);
+
+    // Room for the name: "target_dir/name" and the terminating NUL
+    let dir_len = name_bytes(target_dir.as_os_str()).len() + 1;
+    let name_max = limits.name_max.min(limits.path_max.saturating_sub(dir_len + 1));
+
+    let name = name_bytes(file_name);
+
+    // Directories are renamed as a whole: "Album.2023" -> "Album.2023 (1)"
+    let (mut stem, extension) = split_name(&name, source_path.is_dir(), &options.compound_extensions);
+
+    // "stem" + "suffix" + "extension", stem truncated to fit
+    let build = |stem: &[u8], suffix: &str| -> Result<PathBuf, Error> {
+        let room = name_max.saturating_sub(suffix.len() + extension.len());
+        let stem = truncate_utf8(stem, room);
+
+        if stem.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidFilename,
+                format!("name too long for {}: {}", target_dir.display(), source_path.display()),
+            ));
+        }
+
+        let mut name = stem.to_vec();
+        name.extend_from_slice(suffix.as_bytes());
+        name.extend_from_slice(extension);
+
+        Ok(target_dir.join(name_from_bytes(name)))
+    };
+
+    let new_path = if name.len() <= name_max {
+        target_dir.join(file_name)
+    } else {
+        build(stem, "")?
+    };
+
+    if !taken(&new_path) {
+        return Ok(new_path);
+    }
+
+    options.validate()?;
+
+    // Continue counting from an existing suffix
+    let mut first = 1;
+
+    if let Some(caps) = counter_regex(&options.suffix).and_then(|re| re.captures(stem)) {
+        let base = caps.get(1).map(|m| m.as_bytes()).unwrap_or_default();
+        // Counters too large to continue from are part of the stem
+        let next = std::str::from_utf8(&caps[2]).ok()
+            .and_then(|n| n.parse::<u64>().ok())
+            .and_then(|n| n.checked_add(1));
+
+        if let Some(next) = next {
+            stem = base;
+            first = next;
+        }
+    }
+
+    let timestamp = utc_timestamp();
+
+    for num in first..=u64::MAX {
+        let mut suffix = options.suffix
+            .replace("{n}", &num.to_string())
+            .replace("{timestamp}", &timestamp);
+
+        // Timestamp alone may collide as well
+        if !options.suffix.contains("{n}") && num > 1 {
+            suffix.push_str(&format!("-{}", num));
+        }
+
+        let new_path = build(stem, &suffix)?;
+
+        if !taken(&new_path) {
+            return Ok(new_path);
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::AlreadyExists,
+        format!("ran out of suffix numbers for {}", source_path.display()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::testdir::temp_dir;
+
+    // Name `source` gets in `dir` where `existing` names are taken
+    fn rename(dir: &Path, source: &str, existing: &[&str], options: &RenameOptions) -> Result<String, Error> {
+        for name in existing {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let destination = rename_destination(Path::new(source), dir, &HashSet::new(), options)?;
+        assert_eq!(destination.parent(), Some(dir));
+
+        Ok(destination.file_name().unwrap().to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn free_name_is_kept() {
+        let dir = temp_dir("destination-free");
+        assert_eq!(rename(&dir, "s/song.mp3", &[], &RenameOptions::default()).unwrap(), "song.mp3");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counter_before_extension() {
+        let dir = temp_dir("destination-counter");
+        let options = RenameOptions::default();
+
+        assert_eq!(rename(&dir, "s/song.mp3", &["song.mp3"], &options).unwrap(), "song (1).mp3");
+        assert_eq!(rename(&dir, "s/song.mp3", &["song (1).mp3"], &options).unwrap(), "song (2).mp3");
+        // Existing counter is continued
+        assert_eq!(rename(&dir, "s/song (2).mp3", &["song (2).mp3"], &options).unwrap(), "song (3).mp3");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reserved_names_are_taken() {
+        let dir = temp_dir("destination-reserved");
+        let reserved: HashSet<PathBuf> = [dir.join("song.mp3")].into_iter().collect();
+        let destination = rename_destination(Path::new("song.mp3"), &dir, &reserved, &RenameOptions::default()).unwrap();
+
+        assert_eq!(destination, dir.join("song (1).mp3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compound_extensions() {
+        let dir = temp_dir("destination-compound");
+        let mut options = RenameOptions::default();
+
+        assert_eq!(rename(&dir, "archive.tar.gz", &["archive.tar.gz"], &options).unwrap(), "archive (1).tar.gz");
+        assert_eq!(rename(&dir, "Backup.TAR.XZ", &["Backup.TAR.XZ"], &options).unwrap(), "Backup (1).TAR.XZ");
+        assert_eq!(rename(&dir, "photos.zip.001", &["photos.zip.001"], &options).unwrap(), "photos.zip (1).001");
+
+        options.compound_extensions.push("zip.001".to_string());
+        assert_eq!(rename(&dir, "photos.zip.001", &[], &options).unwrap(), "photos (1).zip.001");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dotfiles_and_names_without_extension() {
+        let dir = temp_dir("destination-dotfiles");
+        let options = RenameOptions::default();
+
+        assert_eq!(rename(&dir, ".bashrc", &[".bashrc"], &options).unwrap(), ".bashrc (1)");
+        assert_eq!(rename(&dir, "README", &["README"], &options).unwrap(), "README (1)");
+        assert_eq!(rename(&dir, ".config.toml", &[".config.toml"], &options).unwrap(), ".config (1).toml");
+        // Only dots before a compound extension
+        assert_eq!(rename(&dir, "..tar.gz", &["..tar.gz"], &options).unwrap(), "..tar (1).gz");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directories_are_not_split() {
+        let dir = temp_dir("destination-directories");
+        let source = temp_dir("destination-directories-source").join("Album.2023");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(dir.join("Album.2023")).unwrap();
+
+        let destination = rename_destination(&source, &dir, &HashSet::new(), &RenameOptions::default()).unwrap();
+        assert_eq!(destination, dir.join("Album.2023 (1)"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn suffix_formats() {
+        let dir = temp_dir("destination-suffix");
+
+        let options = RenameOptions { suffix: "_{n}".to_string(), ..RenameOptions::default() };
+        assert_eq!(rename(&dir, "a.txt", &["a.txt"], &options).unwrap(), "a_1.txt");
+        assert_eq!(rename(&dir, "a_1.txt", &["a_1.txt"], &options).unwrap(), "a_2.txt");
+
+        let options = RenameOptions { suffix: "_{timestamp}".to_string(), ..RenameOptions::default() };
+        let renamed = rename(&dir, "b.txt", &["b.txt"], &options).unwrap();
+        assert!(regex::Regex::new(r"^b_\d{8}-\d{6}\.txt$").unwrap().is_match(&renamed), "{}", renamed);
+
+        // Same timestamp taken already
+        let again = rename(&dir, "b.txt", &[&renamed], &options).unwrap();
+        assert_eq!(again, renamed.replace(".txt", "-2.txt"));
+
+        let options = RenameOptions { suffix: " copy".to_string(), ..RenameOptions::default() };
+        assert_eq!(rename(&dir, "c.txt", &["c.txt"], &options).unwrap_err().kind(), ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counter_overflow_is_part_of_the_stem() {
+        let dir = temp_dir("destination-overflow");
+        let name = "foo (18446744073709551615).txt";
+
+        assert_eq!(
+            rename(&dir, name, &[name], &RenameOptions::default()).unwrap(),
+            "foo (18446744073709551615) (1).txt",
+        );
+
+        let name = "foo (99999999999999999999999).txt";
+        assert_eq!(
+            rename(&dir, name, &[name], &RenameOptions::default()).unwrap(),
+            "foo (99999999999999999999999) (1).txt",
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let dir = temp_dir("destination-truncate");
+        let name_max = NameLimits::of(&dir).name_max;
+        let options = RenameOptions::default();
+
+        let long = format!("{}.mp3", "a".repeat(name_max));
+        let renamed = rename(&dir, &long, &[], &options).unwrap();
+        assert_eq!(renamed.len(), name_max);
+        assert!(renamed.ends_with("a.mp3"));
+
+        let renamed = rename(&dir, &long, &[&renamed], &options).unwrap();
+        assert_eq!(renamed.len(), name_max);
+        assert!(renamed.ends_with("a (1).mp3"));
+
+        // "ä" is two bytes, truncated names never end with half of it
+        let long = format!("{}.mp3", "ä".repeat(name_max));
+        let renamed = rename(&dir, &long, &[], &options).unwrap();
+        assert!(renamed.len() <= name_max && renamed.len() >= name_max - 1);
+        assert!(renamed.ends_with("ä.mp3"));
+
+        let long_extension = format!("a.{}", "x".repeat(name_max));
+        assert_eq!(rename(&dir, &long_extension, &[], &options).unwrap_err().kind(), ErrorKind::InvalidFilename);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
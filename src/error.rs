@@ -0,0 +1,55 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Step of a run where a `FileError` happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    // Reading source directories
+    Scan,
+    // Resolving the destination
+    Plan,
+    // Moving, copying, linking or deleting
+    Execute,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Scan => write!(f, "scanning"),
+            Stage::Plan => write!(f, "planning"),
+            Stage::Execute => write!(f, "executing"),
+        }
+    }
+}
+
+/// Failure with one file or directory. Runs collect these and keep going
+/// with the other files instead of stopping at the first error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileError {
+    #[serde(with = "crate::os_name::path")]
+    pub path: PathBuf,
+    pub stage: Stage,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(path: &Path, stage: Stage, error: &io::Error) -> FileError {
+        FileError {
+            path: path.to_path_buf(),
+            stage,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} failed: {}", self.path.display(), self.stage, self.message)
+    }
+}
+
+impl std::error::Error for FileError {}
@@ -0,0 +1,251 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::journal::{Journal, JournalAction};
+use crate::plan::{Action, PlannedMove, SortPlan};
+use crate::transfer::{copy_path, identical, link_path, move_path, remove_path};
+
+/// What happened to a planned move
+#[derive(Debug)]
+pub enum Outcome {
+    Moved,
+    // Dry run, nothing was touched
+    NotMoved,
+    Failed(Error),
+}
+
+/// Applies a `SortPlan` to the file system
+#[derive(Debug)]
+pub struct Executor {
+    // If false, only report what would be done
+    pub move_files: bool,
+    // Copy, verify and delete when source and destination are on different
+    // file systems
+    pub copy_fallback: bool,
+    // Executed moves are recorded here
+    pub journal: Option<Journal>,
+    // Keep replaced destinations under a hidden name for undo. Without a
+    // journal they are always deleted, as nothing would restore them.
+    pub keep_replaced: bool,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor {
+            move_files: false,
+            copy_fallback: true,
+            journal: None,
+            keep_replaced: true,
+        }
+    }
+}
+
+impl Executor {
+    pub fn new(move_files: bool) -> Executor {
+        Executor {
+            move_files,
+            ..Executor::default()
+        }
+    }
+
+    /// Move (or copy or link, see `PlannedMove::action`) one file, refusing
+    /// to overwrite an existing destination unless `PlannedMove::replace`
+    /// is set. Dry runs check the source and destination as well, so that
+    /// stale plans fail the same way.
+    pub fn apply_move(&self, m: &PlannedMove) -> Outcome {
+        if m.source.symlink_metadata().is_err() {
+            return Outcome::Failed(Error::new(
+                ErrorKind::NotFound,
+                format!("source no longer exists: {}", m.source.display()),
+            ));
+        }
+
+        let exists = m.destination.symlink_metadata().is_ok();
+
+        if exists && !m.replace && m.action != Action::Delete {
+            return Outcome::Failed(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("destination exists: {}", m.destination.display()),
+            ));
+        }
+
+        if !self.move_files {
+            return Outcome::NotMoved;
+        }
+
+        // Replaced destination, see `replace`
+        let (done, action) = match m.action {
+            Action::Delete => (delete_identical(&m.source, &m.destination).map(|_| None), JournalAction::Delete),
+            _ if exists => (self.replace(m), JournalAction::from(m.action)),
+            _ => (self.transfer(m).map(|_| None), JournalAction::from(m.action)),
+        };
+
+        let replaced = match done {
+            Ok(r) => r,
+            Err(e) => return Outcome::Failed(e),
+        };
+
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.record(action, &m.source, &m.destination, replaced.as_deref()) {
+                return Outcome::Failed(Error::new(e.kind(), format!(
+                    "moved to {} but writing journal {} failed: {}",
+                    m.destination.display(), journal.path().display(), e
+                )));
+            }
+        }
+
+        Outcome::Moved
+    }
+
+    fn transfer(&self, m: &PlannedMove) -> Result<(), Error> {
+        match m.action {
+            Action::Move => move_path(&m.source, &m.destination, self.copy_fallback),
+            Action::Copy => copy_path(&m.source, &m.destination),
+            Action::Link => link_path(&m.source, &m.destination),
+            Action::Delete => delete_identical(&m.source, &m.destination),
+        }
+    }
+
+    // Existing destination is set aside under a hidden name next to it and
+    // restored if the transfer fails. It is kept so that undo can put it
+    // back (see `keep_replaced`), returns the hidden name then.
+    fn replace(&self, m: &PlannedMove) -> Result<Option<PathBuf>, Error> {
+        let backup = backup_path(&m.destination);
+        fs::rename(&m.destination, &backup)?;
+
+        if let Err(e) = self.transfer(m) {
+            fs::rename(&backup, &m.destination)?;
+            return Err(e);
+        }
+
+        if self.keep_replaced && self.journal.is_some() {
+            return Ok(Some(backup));
+        }
+
+        remove_path(&backup).map_err(|e| Error::new(e.kind(), format!(
+            "replaced {} but removing the replaced file {} failed: {}",
+            m.destination.display(), backup.display(), e
+        )))?;
+
+        Ok(None)
+    }
+
+    /// Apply all moves of the plan, `report` is called after each move
+    pub fn execute<F>(&self, plan: &SortPlan, mut report: F)
+        where F: FnMut(&PlannedMove, &Outcome) {
+        for m in &plan.moves {
+            let outcome = self.apply_move(m);
+            report(m, &outcome);
+        }
+    }
+}
+
+// Unused hidden name next to `path`, names which are not valid UTF-8 are kept as they are
+fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+
+    (0..)
+        .map(|n| {
+            let mut backup = OsString::from(".");
+            backup.push(name);
+            backup.push(format!(".lajittelia-{}", n));
+            path.with_file_name(backup)
+        })
+        .find(|p| p.symlink_metadata().is_err())
+        .unwrap_or_default()
+}
+
+// Remove `source` after checking again that `existing` is identical
+fn delete_identical(source: &Path, existing: &Path) -> Result<(), Error> {
+    if !identical(source, existing)? {
+        return Err(Error::other(format!(
+            "not deleting {}: no longer identical to {}",
+            source.display(), existing.display()
+        )));
+    }
+
+    fs::remove_file(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testdir::temp_dir;
+
+    // Replace `existing` in `dir` with a new file
+    fn replace_move(dir: &Path, existing: &str) -> PlannedMove {
+        fs::write(dir.join("new"), "new").unwrap();
+        fs::write(dir.join(existing), "old").unwrap();
+
+        PlannedMove {
+            source: dir.join("new"),
+            alias: "foo".to_string(),
+            target_dir: dir.to_path_buf(),
+            destination: dir.join(existing),
+            is_dir: false,
+            action: Action::Move,
+            score: 100,
+            replace: true,
+        }
+    }
+
+    // Names in `dir` other than the destination
+    fn others(dir: &Path, destination: &Path) -> Vec<PathBuf> {
+        let mut names: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p != destination)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn replaced_file_is_kept_with_journal() {
+        let dir = temp_dir("executor-keep");
+        let m = replace_move(&dir, "song.mp3");
+        let executor = Executor {
+            journal: Some(Journal::open(&dir.join("journal").join("journal.jsonl")).unwrap()),
+            ..Executor::new(true)
+        };
+
+        assert!(matches!(executor.apply_move(&m), Outcome::Moved));
+        assert_eq!(fs::read_to_string(&m.destination).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dir.join(".song.mp3.lajittelia-0")).unwrap(), "old");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_file_is_deleted_without_journal_or_backup() {
+        let dir = temp_dir("executor-delete");
+
+        for executor in [Executor::new(true), Executor { keep_replaced: false, ..Executor::new(true) }] {
+            let m = replace_move(&dir, "song.mp3");
+
+            assert!(matches!(executor.apply_move(&m), Outcome::Moved));
+            assert_eq!(fs::read_to_string(&m.destination).unwrap(), "new");
+            assert_eq!(others(&dir, &m.destination), Vec::<PathBuf>::new());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backup_name_keeps_invalid_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = temp_dir("executor-backup-name");
+        let name = OsStr::from_bytes(b"caf\xe9.mp3");
+        let existing = dir.join(name);
+        fs::write(&existing, "").unwrap();
+
+        assert_eq!(backup_path(&existing), dir.join(OsStr::from_bytes(b".caf\xe9.mp3.lajittelia-0")));
+        fs::write(dir.join(OsStr::from_bytes(b".caf\xe9.mp3.lajittelia-0")), "").unwrap();
+        assert_eq!(backup_path(&existing), dir.join(OsStr::from_bytes(b".caf\xe9.mp3.lajittelia-1")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Which source files are considered for sorting. Glob patterns are case
+/// insensitive and matched against both the file name and the path relative
+/// to the source directory: "*.nfo" matches "a/b.nfo" by its name,
+/// "extras/*" matches "extras/b.nfo" by its path.
+///
+/// A file is sorted when it matches an include pattern (or there are none),
+/// has an allowed extension (or none are listed), and matches no exclude
+/// pattern nor denied extension. Directories are only checked against
+/// exclude patterns, excluded directories are not scanned.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    // Lowercase, without the leading dot
+    extensions: Vec<String>,
+    exclude_extensions: Vec<String>,
+}
+
+// Case insensitive set, `*` does not match path separators
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut set = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid glob pattern {:?}: {}", pattern, e),
+            ))?;
+
+        set.add(glob);
+    }
+
+    set.build()
+        .map(Some)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+// ".MKV" -> "mkv"
+fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions.iter()
+        .map(|e| e.trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+// Does the name end with ".ext", compound extensions such as "tar.gz" work as well
+fn has_extension(name: &str, extensions: &[String]) -> bool {
+    let name = name.to_lowercase();
+
+    extensions.iter().any(|ext| {
+        name.len() > ext.len() + 1
+            && name.ends_with(ext.as_str())
+            && name[..name.len() - ext.len()].ends_with('.')
+    })
+}
+
+impl FileFilter {
+    /// Fails if a glob pattern is invalid
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        extensions: &[String],
+        exclude_extensions: &[String],
+    ) -> Result<FileFilter, Error> {
+        Ok(FileFilter {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            extensions: normalize_extensions(extensions),
+            exclude_extensions: normalize_extensions(exclude_extensions),
+        })
+    }
+
+    /// Nothing is filtered out
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none()
+            && self.exclude.is_none()
+            && self.extensions.is_empty()
+            && self.exclude_extensions.is_empty()
+    }
+
+    /// Should `relative`, a path relative to its source directory, be sorted
+    /// (or scanned, for directories)
+    pub fn accepts(&self, relative: &Path, is_dir: bool) -> bool {
+        let name = match relative.file_name() {
+            Some(n) => n.to_string_lossy(),
+            None => return true,
+        };
+
+        let matches = |set: &GlobSet| set.is_match(name.as_ref()) || set.is_match(relative);
+
+        if self.exclude.as_ref().is_some_and(matches) {
+            return false;
+        }
+
+        if is_dir {
+            return true;
+        }
+
+        if self.include.as_ref().is_some_and(|set| !matches(set)) {
+            return false;
+        }
+
+        if !self.extensions.is_empty() && !has_extension(&name, &self.extensions) {
+            return false;
+        }
+
+        !has_extension(&name, &self.exclude_extensions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn filter(include: &[&str], exclude: &[&str], extensions: &[&str], exclude_extensions: &[&str]) -> FileFilter {
+        FileFilter::new(&strings(include), &strings(exclude), &strings(extensions), &strings(exclude_extensions)).unwrap()
+    }
+
+    #[test]
+    fn globs_match_name_or_relative_path() {
+        let filter = filter(&[], &["*.NFO", "extras/*"], &[], &[]);
+
+        assert!(!filter.accepts(Path::new("a/b.nfo"), false));
+        assert!(!filter.accepts(Path::new("extras/b.mkv"), false));
+        // `*` doesn't cross directories
+        assert!(filter.accepts(Path::new("extras/sub/b.mkv"), false));
+        assert!(filter.accepts(Path::new("a/extras.mkv"), false));
+        // Excluded directories are not scanned
+        assert!(!filter.accepts(Path::new("a/extras.nfo"), true));
+    }
+
+    #[test]
+    fn include_and_extensions_apply_to_files_only() {
+        let filter = filter(&["*live*"], &[], &[".MP3", "tar.gz"], &[]);
+
+        assert!(filter.accepts(Path::new("Foo Live.mp3"), false));
+        assert!(filter.accepts(Path::new("foo live.tar.gz"), false));
+        assert!(!filter.accepts(Path::new("foo live.gz"), false));
+        assert!(!filter.accepts(Path::new("foo studio.mp3"), false));
+        assert!(!filter.accepts(Path::new("live.flac"), false));
+        assert!(filter.accepts(Path::new("studio"), true));
+    }
+
+    #[test]
+    fn extension_needs_a_name_before_it() {
+        let filter = filter(&[], &[], &[], &["mp3"]);
+
+        assert!(!filter.accepts(Path::new("song.mp3"), false));
+        assert!(filter.accepts(Path::new(".mp3"), false));
+        assert!(filter.accepts(Path::new("mp3"), false));
+        assert!(filter.accepts(Path::new("song.xmp3"), false));
+    }
+
+    #[test]
+    fn invalid_glob_fails() {
+        let e = FileFilter::new(&strings(&["[a"]), &[], &[], &[]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("\"[a\""), "{}", e);
+
+        assert!(FileFilter::default().is_empty());
+        assert!(!filter(&[], &[], &[], &["mp3"]).is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::busy::{all_paths, snapshot};
+use crate::plan::Action;
+use crate::transfer::{copy_path, move_path, remove_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalAction {
+    Move,
+    // Source was kept, undo removes the copy
+    Copy,
+    // Hard link, undo removes the link
+    Link,
+    // Source was identical to the destination and deleted, undo copies
+    // the destination back
+    Delete,
+    Undo,
+}
+
+impl From<Action> for JournalAction {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Move => JournalAction::Move,
+            Action::Copy => JournalAction::Copy,
+            Action::Link => JournalAction::Link,
+            Action::Delete => JournalAction::Delete,
+        }
+    }
+}
+
+/// One line in the journal. Undo entries repeat the source and destination
+/// of the move they reverse.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub run_id: String,
+    pub action: JournalAction,
+    // Seconds since UNIX epoch
+    pub timestamp: u64,
+    #[serde(with = "crate::os_name::path")]
+    pub source: PathBuf,
+    #[serde(with = "crate::os_name::path")]
+    pub destination: PathBuf,
+    // Total size of everything inside for directories
+    pub size: u64,
+    // Destination modification time after the move, nanoseconds since UNIX
+    // epoch, the newest of everything inside for directories
+    pub modified: Option<u64>,
+    // Existing destination which was replaced, kept under this hidden name
+    // so that undo can put it back
+    #[serde(default, with = "crate::os_name::option", skip_serializing_if = "Option::is_none")]
+    pub replaced: Option<PathBuf>,
+}
+
+/// Append-only journal of executed moves, one JSON object per line
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    run_id: String,
+    file: File,
+}
+
+/// Default journal location: `$XDG_DATA_HOME/lajittelia/journal.jsonl`
+pub fn default_journal_path() -> Option<PathBuf> {
+    let data_dir = match env::var_os("XDG_DATA_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => match env::var_os("HOME") {
+            Some(h) => PathBuf::from(h).join(".local").join("share"),
+            None => PathBuf::from(env::var_os("APPDATA")?),
+        },
+    };
+
+    Some(data_dir.join("lajittelia").join("journal.jsonl"))
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn nanos(t: SystemTime) -> Option<u64> {
+    u64::try_from(t.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+// Size and newest modification time, of everything inside for directories
+// so that changes to the contents are noticed as well
+fn fingerprint(path: &Path) -> (u64, Option<u64>) {
+    let (size, modified) = snapshot(&all_paths(path));
+    (size, modified.and_then(nanos))
+}
+
+// Paths are recorded absolute so that undo works from any directory
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl Journal {
+    /// Open journal for appending, a new run id is generated
+    pub fn open(path: &Path) -> Result<Journal, Error> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        let run_id = format!("{}-{}", unix_time(SystemTime::now()), process::id());
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            run_id,
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    fn append(&self, entry: &JournalEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        (&self.file).write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /// Record executed move (or copy or link), call after `destination` is in
+    /// place. `replaced` is where the file it replaced was set aside.
+    pub fn record(&self, action: JournalAction, source: &Path, destination: &Path, replaced: Option<&Path>) -> Result<(), Error> {
+        let (size, modified) = fingerprint(destination);
+
+        let entry = JournalEntry {
+            run_id: self.run_id.clone(),
+            action,
+            timestamp: unix_time(SystemTime::now()),
+            source: absolute(source),
+            destination: absolute(destination),
+            size,
+            modified,
+            replaced: replaced.map(absolute),
+        };
+
+        self.append(&entry)
+    }
+
+    /// Record that `original` move was reversed
+    pub fn record_undo(&self, original: &JournalEntry) -> Result<(), Error> {
+        let entry = JournalEntry {
+            action: JournalAction::Undo,
+            timestamp: unix_time(SystemTime::now()),
+            ..original.clone()
+        };
+
+        self.append(&entry)
+    }
+}
+
+/// Read all journal entries in order
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, Error> {
+    let file = File::open(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let reader = BufReader::new(file);
+    let mut entries = Vec::new();
+
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: JournalEntry = serde_json::from_str(&line).map_err(|e| Error::new(
+            ErrorKind::InvalidData,
+            format!("{}:{}: {}", path.display(), n + 1, e),
+        ))?;
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Run ids in the order they appear in the journal with their move counts
+pub fn journal_runs(entries: &[JournalEntry]) -> Vec<(String, usize)> {
+    let mut runs: Vec<(String, usize)> = Vec::new();
+
+    for e in entries.iter().filter(|e| e.action != JournalAction::Undo) {
+        match runs.iter_mut().find(|(id, _)| *id == e.run_id) {
+            Some((_, count)) => *count += 1,
+            None => runs.push((e.run_id.clone(), 1)),
+        }
+    }
+
+    runs
+}
+
+/// Why a move can't be undone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndoBlocked {
+    AlreadyUndone,
+    DestinationMissing,
+    // Size or modification time differs from the journal
+    DestinationModified,
+    SourceExists,
+    // File set aside when the destination replaced it is gone
+    ReplacedMissing,
+}
+
+impl fmt::Display for UndoBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndoBlocked::AlreadyUndone => write!(f, "already undone"),
+            UndoBlocked::DestinationMissing => write!(f, "destination no longer exists"),
+            UndoBlocked::DestinationModified => write!(f, "destination modified after the move"),
+            UndoBlocked::SourceExists => write!(f, "original path is taken"),
+            UndoBlocked::ReplacedMissing => write!(f, "replaced file no longer exists"),
+        }
+    }
+}
+
+/// Move of a run to be reversed
+#[derive(Debug, Clone)]
+pub struct UndoItem {
+    // 1-based index of the move within its run
+    pub index: usize,
+    pub entry: JournalEntry,
+    pub blocked: Option<UndoBlocked>,
+}
+
+/// Moves of `run_id` to be undone, newest first. `selected` limits to
+/// given 1-based entry indexes, empty selects the whole run.
+pub fn plan_undo(entries: &[JournalEntry], run_id: &str, selected: &[usize]) -> Vec<UndoItem> {
+    let undone: HashSet<(&Path, &Path)> = entries.iter()
+        .filter(|e| e.run_id == run_id && e.action == JournalAction::Undo)
+        .map(|e| (e.source.as_path(), e.destination.as_path()))
+        .collect();
+
+    let mut items: Vec<UndoItem> = entries.iter()
+        .filter(|e| e.run_id == run_id && e.action != JournalAction::Undo)
+        .enumerate()
+        .map(|(i, e)| (i + 1, e))
+        .filter(|(i, _)| selected.is_empty() || selected.contains(i))
+        .map(|(index, e)| {
+            let blocked = if undone.contains(&(e.source.as_path(), e.destination.as_path())) {
+                Some(UndoBlocked::AlreadyUndone)
+            } else if e.destination.symlink_metadata().is_err() {
+                Some(UndoBlocked::DestinationMissing)
+            } else if fingerprint(&e.destination) != (e.size, e.modified) {
+                Some(UndoBlocked::DestinationModified)
+            } else if matches!(e.action, JournalAction::Move | JournalAction::Delete) && e.source.symlink_metadata().is_ok() {
+                Some(UndoBlocked::SourceExists)
+            } else if e.replaced.as_ref().is_some_and(|r| r.symlink_metadata().is_err()) {
+                Some(UndoBlocked::ReplacedMissing)
+            } else {
+                None
+            };
+
+            UndoItem {
+                index,
+                entry: e.clone(),
+                blocked,
+            }
+        })
+        .collect();
+
+    // Reverse order, so that later moves are undone first
+    items.reverse();
+    items
+}
+
+/// Move destination back to the original path (or remove copy or link,
+/// or copy back a deleted duplicate), put back the file it replaced and
+/// record it in the journal
+pub fn undo_move(journal: &Journal, item: &UndoItem, copy_fallback: bool) -> Result<(), Error> {
+    if let Some(blocked) = item.blocked {
+        return Err(Error::other(blocked.to_string()));
+    }
+
+    let (source, destination) = (&item.entry.source, &item.entry.destination);
+
+    if matches!(item.entry.action, JournalAction::Move | JournalAction::Delete) {
+        if let Some(parent) = source.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    match item.entry.action {
+        JournalAction::Move => move_path(destination, source, copy_fallback)?,
+        JournalAction::Delete => copy_path(destination, source)?,
+        _ => remove_path(destination)?,
+    }
+
+    if let Some(replaced) = &item.entry.replaced {
+        fs::rename(replaced, destination)?;
+    }
+
+    journal.record_undo(&item.entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::executor::{Executor, Outcome};
+    use crate::plan::PlannedMove;
+    use crate::testdir::temp_dir;
+
+    // Moves of the only run in the journal of `journal`
+    fn undo_items(journal: &Journal) -> Vec<UndoItem> {
+        plan_undo(&read_journal(journal.path()).unwrap(), journal.run_id(), &[])
+    }
+
+    #[test]
+    fn modified_destination_blocks_undo() {
+        let dir = temp_dir("journal-modified");
+        let journal = Journal::open(&dir.join("journal.jsonl")).unwrap();
+        fs::create_dir_all(dir.join("album")).unwrap();
+        fs::write(dir.join("song.mp3"), "song").unwrap();
+        fs::write(dir.join("album").join("track.mp3"), "track").unwrap();
+
+        journal.record(JournalAction::Move, &dir.join("old-song.mp3"), &dir.join("song.mp3"), None).unwrap();
+        journal.record(JournalAction::Move, &dir.join("old-album"), &dir.join("album"), None).unwrap();
+        assert_eq!(undo_items(&journal).iter().map(|i| i.blocked).collect::<Vec<_>>(), vec![None, None]);
+
+        fs::write(dir.join("song.mp3"), "changed").unwrap();
+        // Changes inside a directory count as well
+        fs::write(dir.join("album").join("track.mp3"), "changed track").unwrap();
+
+        let items = undo_items(&journal);
+        assert_eq!(
+            items.iter().map(|i| i.blocked).collect::<Vec<_>>(),
+            vec![Some(UndoBlocked::DestinationModified); 2],
+        );
+        assert!(undo_move(&journal, &items[0], true).is_err());
+        assert!(dir.join("album").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_replaced_file() {
+        let dir = temp_dir("journal-replaced");
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("song.mp3"), "new").unwrap();
+        fs::write(dir.join("target").join("song.mp3"), "old").unwrap();
+
+        let executor = Executor {
+            journal: Some(Journal::open(&dir.join("journal.jsonl")).unwrap()),
+            ..Executor::new(true)
+        };
+        let m = PlannedMove {
+            source: dir.join("song.mp3"),
+            alias: "song".to_string(),
+            target_dir: dir.join("target"),
+            destination: dir.join("target").join("song.mp3"),
+            is_dir: false,
+            action: Action::Move,
+            score: 100,
+            replace: true,
+        };
+        assert!(matches!(executor.apply_move(&m), Outcome::Moved));
+
+        let journal = executor.journal.as_ref().unwrap();
+        let items = undo_items(journal);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].blocked, None);
+        assert!(items[0].entry.replaced.is_some());
+
+        undo_move(journal, &items[0], true).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("song.mp3")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dir.join("target").join("song.mp3")).unwrap(), "old");
+        assert_eq!(fs::read_dir(dir.join("target")).unwrap().count(), 1);
+        assert_eq!(undo_items(journal)[0].blocked, Some(UndoBlocked::AlreadyUndone));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_directory_moved_across_file_systems() {
+        let dir = temp_dir("journal-cross-device");
+        // Another file system if there is one, the copy fallback is used below either way
+        let shm = Path::new("/dev/shm");
+        let other = if shm.is_dir() { shm.join(dir.file_name().unwrap()) } else { dir.join("other") };
+        let _ = fs::remove_dir_all(&other);
+        fs::create_dir_all(&other).unwrap();
+
+        let (source, destination) = (dir.join("album"), other.join("album"));
+        fs::create_dir_all(source.join("cd1")).unwrap();
+        fs::write(source.join("cd1").join("track.mp3"), "track").unwrap();
+        fs::write(source.join("cover.jpg"), "cover").unwrap();
+
+        // What `move_path` does when renaming fails with CrossesDevices
+        crate::transfer::copy_verify_delete(&source, &destination).unwrap();
+
+        let journal = Journal::open(&dir.join("journal.jsonl")).unwrap();
+        journal.record(JournalAction::Move, &source, &destination, None).unwrap();
+
+        let items = undo_items(&journal);
+        assert_eq!(items[0].blocked, None);
+        undo_move(&journal, &items[0], true).unwrap();
+
+        assert!(!destination.exists());
+        assert_eq!(fs::read_to_string(source.join("cd1").join("track.mp3")).unwrap(), "track");
+        assert_eq!(fs::read_to_string(source.join("cover.jpg")).unwrap(), "cover");
+        fs::remove_dir_all(&other).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
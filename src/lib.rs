@@ -0,0 +1,56 @@
+//! Sort files into target directory matching target directory name(s).
+//!
+//! Typical use:
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//!
+//! use lajittelia::{AliasIndex, Executor, Matcher, PlanOptions, SortPlan};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let index = AliasIndex::from_target(Path::new("/mnt/nas/sorted"))?;
+//! let matcher = Matcher::new(&index)?;
+//! let sources = [PathBuf::from("/mnt/nas/not-sorted")];
+//! let plan = SortPlan::build(&index, &matcher, &sources, &PlanOptions::default())?;
+//!
+//! Executor::new(true).execute(&plan, |m, outcome| println!("{:?}: {:?}", m.source, outcome));
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod alias;
+pub mod busy;
+pub mod config;
+pub mod destination;
+pub mod error;
+pub mod executor;
+pub mod filter;
+pub mod journal;
+pub mod lint;
+pub mod matcher;
+mod os_name;
+pub mod plan;
+pub mod policy;
+pub mod report;
+pub mod scan;
+#[cfg(test)]
+mod testdir;
+pub mod transfer;
+pub mod watch;
+
+pub use alias::{AliasIndex, AliasOrigin, DirRules};
+pub use busy::{check_busy, BusyOptions};
+pub use config::{Config, Profile};
+pub use destination::{rename_destination, NameLimits, RenameOptions};
+pub use error::{FileError, Stage};
+pub use executor::{Executor, Outcome};
+pub use filter::FileFilter;
+pub use journal::{Journal, JournalAction, JournalEntry};
+pub use lint::{check_aliases, Problem};
+pub use matcher::{AliasTrial, Explanation, FuzzyOptions, Hit, Match, Matcher};
+pub use plan::{Action, AmbiguousMatch, PlanOptions, PlannedMove, ReviewMatch, SortPlan};
+pub use policy::{Collision, CollisionPolicy, MultipleMatchPolicy, Resolution};
+pub use report::{plan_records, OutputFormat, Record, RecordStatus, ReportWriter, RunOutcome, Summary, REPORT_VERSION};
+pub use scan::{scan_sources, Scan, ScanOptions, SkipReason, Skipped};
+pub use transfer::{checksum, copy_path, copy_verify_delete, identical, link_path, move_path};
+pub use watch::{watch, Settler, WatchEvent, WatchOptions};
@@ -0,0 +1,271 @@
+// Checks for aliases which make sorting ambiguous or surprising
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::alias::{AliasIndex, AliasOrigin};
+
+/// Aliases this short are reported, see `Problem::Short`
+pub const SHORT_ALIAS: usize = 2;
+
+/// Words common in file names which make poor aliases
+pub const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "at", "by", "de", "feat", "for", "from", "ft", "hd", "in", "is", "it", "live",
+    "mix", "new", "of", "official", "on", "or", "part", "remix", "the", "to", "video", "vs", "with",
+];
+
+/// Problem found in the aliases of a target directory
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    // Same alias in more than one directory, only the last one read is used
+    Duplicate { alias: String, dirs: Vec<PathBuf> },
+    // Same alias spelled with different case in more than one directory
+    CaseVariant { spellings: Vec<String>, dirs: Vec<PathBuf> },
+    // Aliases which differ only by whitespace: "foo bar" and "foo  bar"
+    WhitespaceVariant { aliases: Vec<String>, dirs: Vec<PathBuf> },
+    // Alias appears as a whole word inside another directory's alias:
+    // "foo" inside "foo fighters"
+    Nested { alias: String, dir: PathBuf, inside: String, inside_dir: PathBuf },
+    // Empty alias from a name like "Foo," or "Foo,,Bar", ignored
+    Empty { dir: PathBuf },
+    // At most `SHORT_ALIAS` characters
+    Short { alias: String, dir: PathBuf },
+    // One of `STOP_WORDS`
+    StopWord { alias: String, dir: PathBuf },
+}
+
+// "\"a\", \"b\"", quoted as directory names may contain ","
+fn join_dirs(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|d| format!("{:?}", d))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// "\"a\", \"b\""
+fn join_aliases(aliases: &[String]) -> String {
+    aliases.iter()
+        .map(|a| format!("{:?}", a))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Duplicate { alias, dirs } => {
+                write!(f, "duplicate alias {:?} in {}", alias, join_dirs(dirs))
+            }
+            Problem::CaseVariant { spellings, dirs } => {
+                write!(f, "case variants {} in {}", join_aliases(spellings), join_dirs(dirs))
+            }
+            Problem::WhitespaceVariant { aliases, dirs } => {
+                write!(f, "whitespace variants {} in {}", join_aliases(aliases), join_dirs(dirs))
+            }
+            Problem::Nested { alias, dir, inside, inside_dir } => {
+                write!(f, "alias {:?} of {:?} is a word in alias {:?} of {:?}", alias, dir, inside, inside_dir)
+            }
+            Problem::Empty { dir } => write!(f, "empty alias in {:?}", dir),
+            Problem::Short { alias, dir } => write!(f, "short alias {:?} in {:?}", alias, dir),
+            Problem::StopWord { alias, dir } => write!(f, "common word {:?} as alias in {:?}", alias, dir),
+        }
+    }
+}
+
+// Word characters as with regex `\b`
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Does `needle` appear in `haystack` with word boundaries on both sides
+fn contains_word(haystack: &str, needle: &str) -> bool {
+    haystack.match_indices(needle).any(|(at, _)| {
+        let before = haystack[..at].chars().next_back();
+        let after = haystack[at + needle.len()..].chars().next();
+
+        let first = needle.chars().next();
+        let last = needle.chars().next_back();
+
+        // Boundary is needed only next to word characters of the needle
+        let start_ok = !first.is_some_and(is_word_char) || !before.is_some_and(is_word_char);
+        let end_ok = !last.is_some_and(is_word_char) || !after.is_some_and(is_word_char);
+
+        start_ok && end_ok
+    })
+}
+
+// Sorted and deduplicated directories of `origins`
+fn dirs_of(origins: &[&AliasOrigin]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = origins.iter().map(|o| o.dir.clone()).collect();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+// Directory relative to the target root for shorter messages
+fn relative(index: &AliasIndex, dir: &Path) -> PathBuf {
+    index.root()
+        .and_then(|r| dir.strip_prefix(r).ok())
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+/// Check aliases of the index for problems, sorted by kind. Directories are
+/// relative to the target root. Regex and glob rules are not checked.
+pub fn check_aliases(index: &AliasIndex) -> Vec<Problem> {
+    let origins: Vec<AliasOrigin> = index.origins()
+        .iter()
+        .map(|o| AliasOrigin {
+            dir: relative(index, &o.dir),
+            ..o.clone()
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+
+    // Same alias after trimming and lowercasing, as in the index
+    let mut by_alias: BTreeMap<String, Vec<&AliasOrigin>> = BTreeMap::new();
+    // Same alias with whitespace collapsed as well
+    let mut by_words: BTreeMap<String, Vec<&AliasOrigin>> = BTreeMap::new();
+
+    for o in &origins {
+        let alias = o.raw.trim().to_lowercase();
+
+        if alias.is_empty() {
+            problems.push(Problem::Empty { dir: o.dir.clone() });
+            continue;
+        }
+
+        let words = alias.split_whitespace().collect::<Vec<_>>().join(" ");
+        by_words.entry(words).or_default().push(o);
+        by_alias.entry(alias).or_default().push(o);
+    }
+
+    for (alias, same) in &by_alias {
+        let dirs = dirs_of(same);
+
+        if dirs.len() > 1 {
+            let mut spellings: Vec<String> = same.iter().map(|o| o.raw.trim().to_string()).collect();
+            spellings.sort();
+            spellings.dedup();
+
+            if spellings.len() > 1 {
+                problems.push(Problem::CaseVariant { spellings, dirs });
+            } else {
+                problems.push(Problem::Duplicate { alias: alias.clone(), dirs });
+            }
+        }
+
+        let dir = same[0].dir.clone();
+
+        if alias.chars().count() <= SHORT_ALIAS {
+            problems.push(Problem::Short { alias: alias.clone(), dir });
+        } else if STOP_WORDS.contains(&alias.as_str()) {
+            problems.push(Problem::StopWord { alias: alias.clone(), dir });
+        }
+    }
+
+    for same in by_words.values() {
+        let mut aliases: Vec<String> = same.iter().map(|o| o.raw.trim().to_lowercase()).collect();
+        aliases.sort();
+        aliases.dedup();
+
+        if aliases.len() > 1 {
+            problems.push(Problem::WhitespaceVariant { aliases, dirs: dirs_of(same) });
+        }
+    }
+
+    // Whole word inside an alias of another directory, with the directory
+    // each alias sorts into
+    let aliases: Vec<(&String, PathBuf)> = by_alias.keys()
+        .filter_map(|a| index.get(a).map(|d| (a, relative(index, d))))
+        .collect();
+
+    for (alias, dir) in &aliases {
+        for (other, other_dir) in &aliases {
+            if alias != other && dir != other_dir && contains_word(other, alias) {
+                problems.push(Problem::Nested {
+                    alias: alias.to_string(),
+                    dir: dir.to_path_buf(),
+                    inside: other.to_string(),
+                    inside_dir: other_dir.to_path_buf(),
+                });
+            }
+        }
+    }
+
+    problems.sort();
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testdir::temp_dir;
+
+    fn check(name: &str, dirs: &[&str]) -> Vec<Problem> {
+        let target = temp_dir(name);
+        for dir in dirs {
+            fs::create_dir_all(target.join(dir)).unwrap();
+        }
+
+        let problems = check_aliases(&AliasIndex::from_target_nested(&target, None).unwrap());
+        fs::remove_dir_all(&target).unwrap();
+        problems
+    }
+
+    #[test]
+    fn words_need_boundaries_next_to_word_characters() {
+        assert!(contains_word("foo fighters", "foo"));
+        assert!(contains_word("the foo-band", "foo"));
+        assert!(contains_word("c++ guide", "c++"));
+        assert!(!contains_word("xc++", "c++"));
+        assert!(!contains_word("foobar", "foo"));
+        assert!(!contains_word("bigfoo", "foo"));
+    }
+
+    #[test]
+    fn aliases_of_one_directory_level() {
+        let problems = check("lint-flat", &["Foo", "Foo Fighters", "Baz,", "AB", "The, Quux"]);
+
+        let mut expected = vec![
+            Problem::Empty { dir: PathBuf::from("Baz,") },
+            Problem::Short { alias: "ab".to_string(), dir: PathBuf::from("AB") },
+            Problem::StopWord { alias: "the".to_string(), dir: PathBuf::from("The, Quux") },
+            Problem::Nested {
+                alias: "foo".to_string(),
+                dir: PathBuf::from("Foo"),
+                inside: "foo fighters".to_string(),
+                inside_dir: PathBuf::from("Foo Fighters"),
+            },
+        ];
+        expected.sort();
+        assert_eq!(problems, expected);
+    }
+
+    #[test]
+    fn aliases_in_several_directories() {
+        let problems = check("lint-nested", &["Rock/Zap", "Pop/Zap", "Rock/Foo Bar", "Pop/foo bar", "Jazz/Qux Quux", "Metal/qux  quux"]);
+
+        let mut expected = vec![
+            Problem::Duplicate {
+                alias: "zap".to_string(),
+                dirs: vec![PathBuf::from("Pop/Zap"), PathBuf::from("Rock/Zap")],
+            },
+            Problem::CaseVariant {
+                spellings: vec!["Foo Bar".to_string(), "foo bar".to_string()],
+                dirs: vec![PathBuf::from("Pop/foo bar"), PathBuf::from("Rock/Foo Bar")],
+            },
+            Problem::WhitespaceVariant {
+                aliases: vec!["qux  quux".to_string(), "qux quux".to_string()],
+                dirs: vec![PathBuf::from("Jazz/Qux Quux"), PathBuf::from("Metal/qux  quux")],
+            },
+        ];
+        expected.sort();
+        assert_eq!(problems, expected);
+    }
+}
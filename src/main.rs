@@ -1,321 +1,1307 @@
-use std::collections::HashMap;
-use std::fs;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use clap::Parser;
-use convert_case::{Case, Casing};
-use rayon::prelude::*;
-use regex::{escape, Regex};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
-fn generate_aliases(
-    target_dirs: &Path,
-) -> Result<HashMap<String, PathBuf>, Error> {
-    if !target_dirs.is_dir() {
-        Error::new(ErrorKind::NotFound, "not a directory");
+use lajittelia::config::default_config_path;
+use lajittelia::journal::{default_journal_path, journal_runs, plan_undo, read_journal, undo_move};
+use lajittelia::{check_aliases, plan_records, Action, AliasIndex, BusyOptions, CollisionPolicy, Config, Executor, FileFilter, FuzzyOptions, Hit,
+                 Journal, JournalAction, Match, Matcher, MultipleMatchPolicy, Outcome, OutputFormat, PlanOptions, PlannedMove, Profile, Record,
+                 RenameOptions, ReportWriter, RunOutcome, ScanOptions, SortPlan, Summary, WatchEvent, WatchOptions};
+
+// Exit codes, 2 is used for invalid command lines
+const EXIT_FATAL: i32 = 1;
+const EXIT_SOME_AMBIGUOUS: i32 = 3;
+const EXIT_SOME_FAILED: i32 = 4;
+
+// Progress messages go to stderr when stdout has machine readable output
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+// Clap turns "{n}" in help texts into a line break, so the counter placeholder is described in words
+const SUFFIX_HELP: &str = "Suffix for renamed files, the letter n in braces is a counter and {timestamp} the current UTC time \
+[default: a space and the counter in parentheses]";
+
+// CLI arguments
+// See: https://docs.rs/clap/latest/clap/
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None,
+args_conflicts_with_subcommands = true,
+subcommand_negates_reqs = true,
+arg_required_else_help = true)]
+struct CLIArgs {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    sort: SortArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(about = "Write what would be moved to a plan file (JSON, or TOML with .toml extension)")]
+    Plan(PlanCommandArgs),
+
+    #[clap(about = "Move files according to a plan file written with the plan command")]
+    Apply(ApplyArgs),
+
+    #[clap(about = "Move files of a previous run back to where they were")]
+    Undo(UndoArgs),
+
+    #[clap(about = "Keep watching source directories and sort files as they arrive")]
+    Watch(WatchArgs),
+
+    #[clap(about = "Sort with a named profile from the configuration file, options given override the profile")]
+    Run(RunArgs),
+
+    #[clap(about = "Check aliases of the target directory for duplicates and other problems")]
+    Check(CheckArgs),
+
+    #[clap(about = "Show how names are normalized and matched against the aliases of the target directory")]
+    Explain(ExplainArgs),
+}
+
+// See CollisionPolicy
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CollisionArg {
+    Skip,
+    Overwrite,
+    Rename,
+    KeepNewer,
+    KeepLarger,
+    DeleteIdentical,
+}
+
+// See MultipleMatchPolicy
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum MultipleArg {
+    Skip,
+    Longest,
+    Earliest,
+    Priority,
+    Fallback,
+    CopyAll,
+    LinkAll,
+}
+
+// See OutputFormat
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FormatArg {
+    Text,
+    Json,
+    Ndjson,
+    Csv,
+}
+
+#[derive(Args, Debug)]
+struct SortArgs {
+    #[clap(flatten)]
+    plan: PlanArgs,
+
+    #[clap(short = 'Y', long, help = "Move files? If enabled, files are actually moved")]
+    move_files: bool,
+
+    #[clap(long, value_name = "FILE",
+    help = "Journal of moved files [default: $XDG_DATA_HOME/lajittelia/journal.jsonl]")]
+    journal: Option<PathBuf>,
+
+    #[clap(long,
+    help = "Delete files replaced with --collision overwrite, keep-newer or keep-larger instead of keeping them hidden for undo")]
+    no_backup: bool,
+
+    #[clap(long, value_enum, value_name = "FORMAT",
+    help = "Output format, progress messages go to stderr with machine readable formats [default: text]")]
+    output_format: Option<FormatArg>,
+}
+
+// Arguments for building a sort plan
+#[derive(Args, Debug)]
+struct PlanArgs {
+    #[clap(short = 't', long, help = "Target directory for sorted files")]
+    target: Option<PathBuf>,
+
+    #[clap(short = 'n', long,
+    help = "Target has nested category directories, files are sorted into the deepest matching directory")]
+    nested: bool,
+
+    #[clap(long, overrides_with = "nested", help = "Turn off --nested of a profile")]
+    no_nested: bool,
+
+    #[clap(long, value_name = "N",
+    help = "Maximum depth of nested target directories (implies --nested)")]
+    target_depth: Option<usize>,
+
+    #[clap(short = 'r', long, help = "Scan source directories recursively")]
+    recursive: bool,
+
+    #[clap(long, overrides_with = "recursive", help = "Turn off --recursive of a profile")]
+    no_recursive: bool,
+
+    #[clap(long, value_name = "N",
+    help = "Maximum directory depth when scanning recursively (implies --recursive)")]
+    max_depth: Option<usize>,
+
+    #[clap(long, help = "Do not descend into directories on other file systems")]
+    one_file_system: bool,
+
+    #[clap(long, overrides_with = "one_file_system", help = "Turn off --one-file-system of a profile")]
+    no_one_file_system: bool,
+
+    #[clap(short = 'D', long,
+    help = "Sort directories as one unit, matched with the directory name")]
+    directories: bool,
+
+    #[clap(long, overrides_with = "directories", help = "Turn off --directories of a profile")]
+    no_directories: bool,
+
+    #[clap(long, value_name = "GLOB",
+    help = "Only sort files whose name or relative path matches, can be repeated")]
+    include: Vec<String>,
+
+    #[clap(long, value_name = "GLOB",
+    help = "Leave files and directories whose name or relative path matches alone, can be repeated")]
+    exclude: Vec<String>,
+
+    #[clap(long = "ext", value_name = "EXT",
+    help = "Only sort files with this extension, can be repeated")]
+    extensions: Vec<String>,
+
+    #[clap(long = "exclude-ext", value_name = "EXT",
+    help = "Leave files with this extension alone, can be repeated")]
+    exclude_extensions: Vec<String>,
+
+    #[clap(long, value_name = "SECONDS",
+    help = "Skip files modified less than this long ago")]
+    min_age: Option<u64>,
+
+    #[clap(long, value_name = "SECONDS",
+    help = "Wait this long and skip files whose size or modification time changed meanwhile")]
+    stable_check: Option<u64>,
+
+    #[clap(long, value_name = "GLOB",
+    help = "Partial download file name pattern in addition to *.part, *.crdownload, *.!qB, *.partial and *.download, can be repeated")]
+    partial: Vec<String>,
+
+    #[clap(long, help = "Skip files open for writing by some process (Linux)")]
+    check_open: bool,
+
+    #[clap(long, overrides_with = "check_open", help = "Turn off --check-open of a profile")]
+    no_check_open: bool,
+
+    #[clap(short = 'm', long, value_enum, value_name = "POLICY",
+    help = "What to do with files matching multiple aliases [default: skip]")]
+    multiple: Option<MultipleArg>,
+
+    #[clap(long = "priority", value_name = "DIR", required_if_eq("multiple", "priority"),
+    help = "Target directory name in priority order for --multiple priority, can be repeated")]
+    priority: Vec<PathBuf>,
+
+    #[clap(long, value_name = "DIR", required_if_eq("multiple", "fallback"),
+    help = "Directory for files matching multiple aliases for --multiple fallback")]
+    ambiguous_dir: Option<PathBuf>,
+
+    #[clap(short = 'c', long, value_enum, value_name = "POLICY",
+    help = "What to do when a file with the same name exists in the target directory [default: rename]")]
+    collision: Option<CollisionArg>,
+
+    #[clap(long, value_name = "FORMAT",
+    help = SUFFIX_HELP)]
+    suffix: Option<String>,
+
+    #[clap(long = "compound-ext", value_name = "EXT",
+    help = "Extension kept together when renaming in addition to tar.gz, tar.bz2, tar.xz and tar.zst, can be repeated")]
+    compound_extensions: Vec<String>,
+
+    #[clap(long, help = "Ignore diacritics when matching: \"Motorhead\" matches \"Motörhead\"")]
+    fold_accents: bool,
+
+    #[clap(long, overrides_with = "fold_accents", help = "Turn off --fold-accents of a profile")]
+    no_fold_accents: bool,
+
+    #[clap(long, help = "Match aliases with typos when a name has no exact matches")]
+    fuzzy: bool,
+
+    #[clap(long, overrides_with = "fuzzy", help = "Turn off --fuzzy of a profile")]
+    no_fuzzy: bool,
+
+    #[clap(long, value_name = "PERCENT",
+    value_parser = clap::value_parser!(u8).range(0..=100),
+    help = "Minimum fuzzy match score [default: 80]")]
+    fuzzy_threshold: Option<u8>,
+
+    #[clap(long, value_name = "PERCENT",
+    value_parser = clap::value_parser!(u8).range(0..=100),
+    help = "Fuzzy matches scoring below this are listed for review instead of moved [default: 90]")]
+    review_below: Option<u8>,
+
+    #[clap(help = "Path(s) to scan for files to be sorted")]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct PlanCommandArgs {
+    #[clap(short = 'o', long, value_name = "FILE", help = "Plan file to write")]
+    output: PathBuf,
+
+    #[clap(flatten)]
+    plan: PlanArgs,
+}
+
+#[derive(Args, Debug)]
+struct ApplyArgs {
+    #[clap(help = "Plan file to apply")]
+    plan: PathBuf,
+
+    #[clap(short = 'Y', long, help = "Move files? If enabled, files are actually moved")]
+    move_files: bool,
+
+    #[clap(long, value_name = "FILE",
+    help = "Journal of moved files [default: $XDG_DATA_HOME/lajittelia/journal.jsonl]")]
+    journal: Option<PathBuf>,
+
+    #[clap(long,
+    help = "Delete files replaced with --collision overwrite, keep-newer or keep-larger instead of keeping them hidden for undo")]
+    no_backup: bool,
+
+    #[clap(long, value_enum, value_name = "FORMAT",
+    help = "Output format, progress messages go to stderr with machine readable formats [default: text]")]
+    output_format: Option<FormatArg>,
+}
+
+#[derive(Args, Debug)]
+struct UndoArgs {
+    #[clap(help = "Run to undo, defaults to the latest run")]
+    run: Option<String>,
+
+    #[clap(short = 'e', long = "entry", value_name = "N",
+    help = "Only undo given entry of the run, can be repeated (see --list)")]
+    entries: Vec<usize>,
+
+    #[clap(short = 'l', long, help = "List entries of the run, or all runs if no run is given")]
+    list: bool,
+
+    #[clap(short = 'Y', long, help = "Move files? If enabled, files are actually moved back")]
+    move_files: bool,
+
+    #[clap(long, value_name = "FILE",
+    help = "Journal of moved files [default: $XDG_DATA_HOME/lajittelia/journal.jsonl]")]
+    journal: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CheckArgs {
+    #[clap(short = 't', long, help = "Target directory to check")]
+    target: PathBuf,
+
+    #[clap(short = 'n', long, help = "Target has nested category directories")]
+    nested: bool,
+
+    #[clap(long, value_name = "N",
+    help = "Maximum depth of nested target directories (implies --nested)")]
+    target_depth: Option<usize>,
+}
+
+// Same options as sorting so that a command line can be explained as is
+#[derive(Args, Debug)]
+#[clap(mut_arg("paths", |a| a.value_name("NAME").required(true)
+    .help("File name(s) to explain, they don't need to exist (use -D for directory names)")))]
+struct ExplainArgs {
+    #[clap(flatten)]
+    plan: PlanArgs,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[clap(help = "Profile name")]
+    profile: String,
+
+    #[clap(long, value_name = "FILE",
+    help = "Configuration file [default: $XDG_CONFIG_HOME/lajittelia/config.toml]")]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    sort: SortArgs,
+}
+
+#[derive(Args, Debug)]
+struct WatchArgs {
+    #[clap(short = 'p', long, help = "Profile to use, options given override the profile")]
+    profile: Option<String>,
+
+    #[clap(long, value_name = "FILE",
+    help = "Configuration file [default: $XDG_CONFIG_HOME/lajittelia/config.toml]")]
+    config: Option<PathBuf>,
+
+    #[clap(long, value_name = "SECONDS", default_value_t = 2.0,
+    help = "Time without file system events before a file is looked at")]
+    debounce: f64,
+
+    #[clap(long, value_name = "SECONDS", default_value_t = 5.0,
+    help = "Time size and modification time must stay the same before a file is sorted")]
+    stable_for: f64,
+
+    #[clap(flatten)]
+    sort: SortArgs,
+}
+
+fn journal_path(journal: Option<PathBuf>) -> PathBuf {
+    match journal.or_else(default_journal_path) {
+        Some(p) => p,
+        None => {
+            eprintln!("can't determine journal location, use --journal");
+            exit(EXIT_FATAL);
+        }
     }
+}
 
-    let mut entries: HashMap<String, PathBuf> = HashMap::new();
+// Check the target directory and return it
+fn check_target(args: &PlanArgs) -> PathBuf {
+    let target = match &args.target {
+        Some(t) => t.clone(),
+        None => {
+            eprintln!("no target directory given, use --target");
+            exit(EXIT_FATAL);
+        }
+    };
 
-    for entry in fs::read_dir(target_dirs)? {
-        let entry = entry?;
+    if !target.is_dir() {
+        eprintln!("not a directory: {}", target.display());
+        exit(EXIT_FATAL);
+    }
 
-        if !entry.path().is_dir() {
-            continue;
+    target
+}
+
+// Check target and source directories, returns the target
+fn check_dirs(args: &PlanArgs) -> PathBuf {
+    let target = check_target(args);
+
+    if args.paths.is_empty() {
+        eprintln!("no directories given to be scanned");
+        exit(EXIT_FATAL);
+    }
+
+    // Only directories as sources
+    for p in args.paths.clone() {
+        if !p.is_dir() {
+            eprintln!("not a directory: {}", p.display());
+            exit(EXIT_FATAL);
         }
+    }
+
+    target
+}
 
-        let name = entry.path().file_name().expect("")
-            .to_str().expect("")
-            .to_string()
-            .to_lowercase();
+fn load_aliases(args: &PlanArgs, target: &Path) -> Result<(AliasIndex, Matcher), Error> {
+    let aliases = match (args.target_depth, args.nested) {
+        (Some(depth), _) => AliasIndex::from_target_nested(target, Some(depth))?,
+        (None, true) => AliasIndex::from_target_nested(target, None)?,
+        (None, false) => AliasIndex::from_target(target)?,
+    };
 
-        if name.contains(",") {
-            // Split with ","
-            for n in name.split(",") {
-                let s = n.trim().to_string();
+    let mut matcher = Matcher::with_folding(&aliases, args.fold_accents)?;
+
+    if args.fuzzy {
+        let defaults = FuzzyOptions::default();
+
+        matcher = matcher.with_fuzzy(FuzzyOptions {
+            threshold: args.fuzzy_threshold.unwrap_or(defaults.threshold),
+            review_below: args.review_below.unwrap_or(defaults.review_below),
+        });
+    }
 
-                if entries.contains_key(&*s) {
-                    Error::new(ErrorKind::AlreadyExists, "exists");
+    Ok((aliases, matcher))
+}
+
+fn plan_options(args: &PlanArgs, target: &Path) -> PlanOptions {
+    PlanOptions {
+        scan: ScanOptions {
+            max_depth: match (args.max_depth, args.recursive) {
+                (Some(depth), _) => Some(depth),
+                (None, true) => None,
+                (None, false) => Some(0),
+            },
+            one_file_system: args.one_file_system,
+            directories: args.directories,
+            filter: match FileFilter::new(&args.include, &args.exclude, &args.extensions, &args.exclude_extensions) {
+                Ok(f) => f,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(EXIT_FATAL);
+                }
+            },
+        },
+        multiple: match args.multiple.unwrap_or(MultipleArg::Skip) {
+            MultipleArg::Skip => MultipleMatchPolicy::Skip,
+            MultipleArg::Longest => MultipleMatchPolicy::Longest,
+            MultipleArg::Earliest => MultipleMatchPolicy::Earliest,
+            MultipleArg::Priority => {
+                if args.priority.is_empty() {
+                    eprintln!("--multiple priority needs at least one --priority directory");
+                    exit(EXIT_FATAL);
                 }
 
-                entries.insert(s, entry.path());
+                MultipleMatchPolicy::Priority(
+                    args.priority.iter().map(|p| target.join(p)).collect()
+                )
             }
-        } else {
-            // Use whole directory name
-            if entries.contains_key(&*name) {
-                Error::new(ErrorKind::AlreadyExists, "exists");
+            MultipleArg::Fallback => {
+                let dir = match &args.ambiguous_dir {
+                    Some(d) => d.clone(),
+                    None => {
+                        eprintln!("--multiple fallback needs --ambiguous-dir");
+                        exit(EXIT_FATAL);
+                    }
+                };
+
+                if !dir.is_dir() {
+                    eprintln!("not a directory: {}", dir.display());
+                    exit(EXIT_FATAL);
+                }
+
+                MultipleMatchPolicy::Fallback(dir)
+            }
+            MultipleArg::CopyAll => MultipleMatchPolicy::CopyAll,
+            MultipleArg::LinkAll => MultipleMatchPolicy::LinkAll,
+        },
+        busy: {
+            let mut busy = BusyOptions::default();
+            busy.partial_patterns.extend(args.partial.iter().cloned());
+            busy.min_age = args.min_age.map(Duration::from_secs);
+            busy.stable_check = args.stable_check.map(Duration::from_secs);
+            busy.check_open = args.check_open;
+
+            if let Err(e) = busy.validate() {
+                eprintln!("{}", e);
+                exit(EXIT_FATAL);
             }
 
-            entries.insert(name, entry.path());
-        }
+            busy
+        },
+        rename: {
+            let mut rename = RenameOptions::default();
+
+            if let Some(suffix) = &args.suffix {
+                rename.suffix = suffix.clone();
+            }
+
+            rename.compound_extensions.extend(args.compound_extensions.iter().cloned());
+
+            if let Err(e) = rename.validate() {
+                eprintln!("{}", e);
+                exit(EXIT_FATAL);
+            }
+
+            rename
+        },
+        collision: match args.collision.unwrap_or(CollisionArg::Rename) {
+            CollisionArg::Skip => CollisionPolicy::Skip,
+            CollisionArg::Overwrite => CollisionPolicy::Overwrite,
+            CollisionArg::Rename => CollisionPolicy::Rename,
+            CollisionArg::KeepNewer => CollisionPolicy::KeepNewer,
+            CollisionArg::KeepLarger => CollisionPolicy::KeepLarger,
+            CollisionArg::DeleteIdentical => CollisionPolicy::DeleteIdentical,
+        },
     }
+}
+
+fn build_plan(args: PlanArgs) -> Result<SortPlan, Error> {
+    let target = check_dirs(&args);
 
-    Ok(entries)
+    status!("Using {} as sorting target directory", target.display());
+
+    let (aliases, matcher) = load_aliases(&args, &target)?;
+
+    if aliases.is_empty() {
+        eprintln!("target directory {} is empty?", target.display());
+        exit(EXIT_FATAL);
+    }
+
+    status!("Finding matches...");
+
+    let options = plan_options(&args, &target);
+
+    SortPlan::build(&aliases, &matcher, &args.paths, &options)
 }
 
-// Sort keys by length
-fn sort_keys(
-    entries: HashMap<String, PathBuf>,
-) -> Result<Vec<String>, Error> {
-    let mut keys: Vec<String> = entries.keys()
-        .map(|x|
-            x.to_string()
-        ).collect();
+// " (score N%)" for fuzzy matches, " (replacing existing)" for overwrites
+fn notes(m: &PlannedMove) -> String {
+    let mut notes = String::new();
 
-    keys.sort_by(|a, b|
-        b.len().cmp(&a.len())
-    );
+    if m.score < 100 {
+        notes.push_str(&format!(" (score {}%)", m.score));
+    }
+
+    if m.replace {
+        notes.push_str(" (replacing existing)");
+    }
 
-    Ok(keys)
+    notes
 }
 
-fn trim_str(mut s: String) -> String {
-    let remove: &[_] = &['_', '.', '-', ' '];
-    s = s.trim_matches(remove).to_string();
-    s
+// Machine readable format, None for text
+fn output_format(arg: Option<FormatArg>) -> Option<OutputFormat> {
+    let format = match arg.unwrap_or(FormatArg::Text) {
+        FormatArg::Text => None,
+        FormatArg::Json => Some(OutputFormat::Json),
+        FormatArg::Ndjson => Some(OutputFormat::Ndjson),
+        FormatArg::Csv => Some(OutputFormat::Csv),
+    };
+
+    STATUS_TO_STDERR.store(format.is_some(), Ordering::Relaxed);
+    format
 }
 
-// Search candidates to be sorted
-fn search_candidates(
-    entries: HashMap<String, PathBuf>,
-    sources: Vec<PathBuf>,
-) -> Result<
-    (
-        // Found
-        HashMap<PathBuf, String>,
-        // Multiple matches
-        Vec<PathBuf>
-    ), Error> {
-    if sources.is_empty() {
-        Error::new(ErrorKind::NotFound, "no sources");
+fn open_journal(executor: &mut Executor, move_files: bool, journal: Option<PathBuf>, no_backup: bool) -> Result<(), Error> {
+    executor.keep_replaced = !no_backup;
+
+    if move_files {
+        let journal = Journal::open(&journal_path(journal))?;
+        status!("Run {} journal: {}", journal.run_id(), journal.path().display());
+        executor.journal = Some(journal);
     }
 
-    let mut multiple_matches: Vec<PathBuf> = Vec::new();
-    let mut candidates: HashMap<PathBuf, String> = HashMap::new();
+    Ok(())
+}
+
+// Execute plan and write a record of every file
+fn report_plan(plan: &SortPlan, move_files: bool, journal: Option<PathBuf>, no_backup: bool, format: OutputFormat) -> Result<Summary, Error> {
+    let mut summary = Summary::from_plan(plan);
+    let mut report = ReportWriter::new(format, std::io::stdout().lock());
+    let mut written = Ok(());
+
+    if !plan.moves.is_empty() {
+        let mut executor = Executor::new(move_files);
+        open_journal(&mut executor, move_files, journal, no_backup)?;
 
-    let aliases = sort_keys(entries).expect("");
-    let mut alias_re: HashMap<String, Regex> = HashMap::new();
+        executor.execute(plan, |m, outcome| {
+            summary.add_outcome(m, outcome);
 
-    for alias in aliases.to_owned() {
-        // Must have boundary
-        let escaped = format!(r"\b{}\b", escape(&alias));
-        alias_re.insert(alias, Regex::new(&escaped).unwrap());
+            if written.is_ok() {
+                written = report.write(Record::from_move(m, outcome));
+            }
+        });
     }
 
-    for dir in sources {
-        if !dir.is_dir() {
-            continue;
+    written?;
+
+    for record in plan_records(plan) {
+        report.write(record)?;
+    }
+
+    report.finish()?;
+    print_summary(&summary);
+
+    Ok(summary)
+}
+
+// Counts and failures at the end of a run
+fn print_summary(summary: &Summary) {
+    if !summary.errors.is_empty() {
+        eprintln!("Failed:");
+
+        for e in &summary.errors {
+            eprintln!("{}", e);
         }
 
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
+        eprintln!();
+    }
 
-            if entry.path().is_dir() {
-                continue;
-            }
+    let counts = [
+        (summary.not_moved, "not moved (dry run)"),
+        (summary.ambiguous, "multiple matches"),
+        (summary.review, "to review"),
+        (summary.unmatched, "unmatched"),
+        (summary.skipped, "skipped"),
+        (summary.errors.len(), "failed"),
+    ];
 
-            let name = PathBuf::from(
-                entry.path().file_name().unwrap()
-            );
+    let mut parts = vec![format!("{} moved", summary.moved)];
+    parts.extend(counts.iter().filter(|(n, _)| *n > 0).map(|(n, what)| format!("{} {}", n, what)));
 
-            let mut modified: String = name.file_stem().unwrap().to_str().unwrap().to_string();
+    status!("Summary: {}", parts.join(", "));
+}
 
-            modified = trim_str(modified);
-            modified = modified.replace(".", " ");
-            modified = modified.to_case(Case::Lower);
+// Execute plan and print what was done. Failures with single files are
+// collected to the summary, errors are returned only when nothing can be done.
+fn execute_plan(plan: &SortPlan, move_files: bool, journal: Option<PathBuf>, no_backup: bool, format: Option<OutputFormat>) -> Result<Summary, Error> {
+    if let Some(format) = format {
+        return report_plan(plan, move_files, journal, no_backup, format);
+    }
 
-            if modified.is_empty() {
-                continue;
-            }
+    let mut summary = Summary::from_plan(plan);
 
-            let alias_matches_lock = Mutex::new(Vec::new());
+    if !plan.moves.is_empty() {
+        println!("Matches:");
 
-            aliases.clone()
-                .par_iter()
-                .for_each(|alias| {
-                    if alias_re[alias].is_match(&modified) {
-                        let a = alias.clone();
-                        alias_matches_lock.lock().unwrap().push(a.to_string());
-                    }
-                });
+        let mut executor = Executor::new(move_files);
+        open_journal(&mut executor, move_files, journal, no_backup)?;
 
-            let alias_matches: Vec<String> = alias_matches_lock.lock().unwrap().to_vec();
+        executor.execute(plan, |m, outcome| {
+            summary.add_outcome(m, outcome);
 
-            if alias_matches.is_empty() {
-                // No matches
-                continue;
-            } else if alias_matches.len() > 1 {
-                // Multiple matches, add to a list
-                multiple_matches.push(entry.path());
-                continue;
+            let (done, not_done, to) = match m.action {
+                Action::Move => ("Moved", "Not moving", "to"),
+                Action::Copy => ("Copied", "Not copying", "to"),
+                Action::Link => ("Linked", "Not linking", "to"),
+                Action::Delete => ("Deleted", "Not deleting", "identical to"),
+            };
+
+            match outcome {
+                Outcome::Moved => {
+                    println!("{} {} {} {}{}", done, m.source.display(), to, m.destination.display(), notes(m));
+                }
+                Outcome::NotMoved => {
+                    println!("{} {} {} {}{}", not_done, m.source.display(), to, m.destination.display(), notes(m));
+                }
+                // Listed with the summary
+                Outcome::Failed(_) => {}
             }
+        });
 
-            candidates.insert(entry.path(), alias_matches[0].clone());
+        println!();
+    }
+
+    if !plan.multiple_matches.is_empty() {
+        println!("Multiple matches (not moved):");
+
+        for m in &plan.multiple_matches {
+            println!("{}", m.source.display())
         }
+
+        println!();
     }
 
-    Ok((candidates, multiple_matches))
+    if !plan.review.is_empty() {
+        println!("Review (low confidence, not moved):");
+
+        for r in &plan.review {
+            println!("{} -> {} ({}, score {}%)", r.source.display(), r.target_dir.display(), r.alias, r.score)
+        }
+
+        println!();
+    }
+
+    if !plan.skipped.is_empty() {
+        println!("Skipped:");
+
+        for s in &plan.skipped {
+            println!("{} ({})", s.path.display(), s.reason)
+        }
+
+        println!();
+    }
+
+    print_summary(&summary);
+
+    Ok(summary)
 }
 
-// Add (N) suffix to path if we have existing dir/file
-// Bugs:
-// - Path as a whole might be too long (example: ISO 9660)
-// - File name might be too long
-fn rename_destination(
-    source_path: PathBuf, // What we're moving
-    target_dir: PathBuf, // To where
-) -> Result<PathBuf, Error> {
-    if !target_dir.is_dir() {
-        Error::new(ErrorKind::InvalidInput, "not dir");
+fn sort(args: SortArgs) -> Result<RunOutcome, Error> {
+    let format = output_format(args.output_format);
+    let plan = build_plan(args.plan)?;
+    execute_plan(&plan, args.move_files, args.journal, args.no_backup, format).map(|s| s.outcome())
+}
+
+// Fill options not given on the command line from `profile`, fails if the
+// profile has invalid policy or format names
+fn apply_profile(args: &mut SortArgs, name: &str, profile: &Profile) -> Result<(), Error> {
+    let plan = &mut args.plan;
+
+    // Policy names as on the command line
+    fn parse<T: ValueEnum>(name: &str, key: &str, value: &Option<String>) -> Result<Option<T>, Error> {
+        value.as_ref()
+            .map(|v| T::from_str(v, true).map_err(|e| Error::new(
+                ErrorKind::InvalidInput,
+                format!("profile {}: invalid {} {:?}: {}", name, key, v, e),
+            )))
+            .transpose()
+    }
+
+    // Lists given on the command line replace the profile's
+    fn list<T: Clone>(cli: &mut Vec<T>, profile: &[T]) {
+        if cli.is_empty() {
+            *cli = profile.to_vec();
+        }
+    }
+
+    if plan.multiple.is_none() {
+        plan.multiple = parse(name, "multiple", &profile.multiple)?;
+    }
+
+    if plan.collision.is_none() {
+        plan.collision = parse(name, "collision", &profile.collision)?;
     }
 
-    // Target name, with possible rename(s), see loop below
-    let mut new_path = PathBuf::from(target_dir.clone());
-    new_path = new_path.join(source_path.file_name().unwrap());
+    plan.suffix = plan.suffix.take().or_else(|| profile.suffix.clone());
+    list(&mut plan.compound_extensions, &profile.compound_extensions);
+
+    plan.target = plan.target.take().or_else(|| profile.target.clone());
+    // Depths imply --nested and --recursive, so --no-nested and --no-recursive turn them off as well
+    plan.nested |= profile.nested && !plan.no_nested;
+    plan.target_depth = plan.target_depth.or(profile.target_depth.filter(|_| !plan.no_nested));
+    plan.recursive |= profile.recursive && !plan.no_recursive;
+    plan.max_depth = plan.max_depth.or(profile.max_depth.filter(|_| !plan.no_recursive));
+    plan.one_file_system |= profile.one_file_system && !plan.no_one_file_system;
+    plan.directories |= profile.directories && !plan.no_directories;
+    list(&mut plan.include, &profile.include);
+    list(&mut plan.exclude, &profile.exclude);
+    list(&mut plan.extensions, &profile.extensions);
+    list(&mut plan.exclude_extensions, &profile.exclude_extensions);
+    plan.min_age = plan.min_age.or(profile.min_age);
+    plan.stable_check = plan.stable_check.or(profile.stable_check);
+    list(&mut plan.partial, &profile.partial);
+    plan.check_open |= profile.check_open && !plan.no_check_open;
+    plan.ambiguous_dir = plan.ambiguous_dir.take().or_else(|| profile.ambiguous_dir.clone());
+    plan.fold_accents |= profile.fold_accents && !plan.no_fold_accents;
+    plan.fuzzy |= profile.fuzzy && !plan.no_fuzzy;
+    plan.fuzzy_threshold = plan.fuzzy_threshold.or(profile.fuzzy_threshold);
+    plan.review_below = plan.review_below.or(profile.review_below);
+
+    list(&mut plan.priority, &profile.priority);
+    list(&mut plan.paths, &profile.sources);
+
+    args.journal = args.journal.take().or_else(|| profile.journal.clone());
+
+    if args.output_format.is_none() {
+        args.output_format = parse(name, "output_format", &profile.output_format)?;
+    }
 
-    let extension = new_path.clone().extension().unwrap().to_owned();
+    Ok(())
+}
 
-    // Capture (N) suffix "example file (1).dat"
-    let re_suffix = Regex::new(r" \((\d+)\)$").unwrap();
+// Read `name` from the configuration file and fill options of `args` with it
+fn use_profile(args: &mut SortArgs, name: &str, config: Option<PathBuf>) -> Result<(), Error> {
+    let path = match config.or_else(default_config_path) {
+        Some(p) => p,
+        None => {
+            eprintln!("can't determine configuration file location, use --config");
+            exit(EXIT_FATAL);
+        }
+    };
 
-    loop {
-        // Loop until we have a new file name
+    let config = Config::load(&path)?;
 
-        if !new_path.exists() {
-            // Move file/dir
-            break;
+    let profile = match config.profile(name) {
+        Some(p) => p,
+        None => {
+            let names: Vec<&str> = config.profiles.keys().map(|k| k.as_str()).collect();
+            eprintln!("profile {} not found in {} (profiles: {})", name, path.display(), names.join(", "));
+            exit(EXIT_FATAL);
         }
+    };
 
-        if !re_suffix.is_match(new_path.file_stem().unwrap().to_str().unwrap()) {
-            // Add " (1)" suffix
-            let fname = PathBuf::from(
-                format!("{} (1).{}",
-                        new_path.file_stem().unwrap().to_str().unwrap(),
-                        extension.clone().to_str().unwrap()
-                )
-            );
+    apply_profile(args, name, profile)?;
 
-            new_path = PathBuf::from(target_dir.clone());
-            new_path = new_path.join(fname.clone());
-        } else {
-            // Increase "(1)" suffix to "(2)"
-
-            // Get suffix number from file name
-            let fname = new_path.file_stem().unwrap();
-            let m = re_suffix.captures(fname.to_str().unwrap()).unwrap();
-            let num: u64 = m.get(1).map(|x|
-                x.as_str().parse().unwrap()
-            ).unwrap();
-
-            // Position where suffix begins
-            let start = m.get(1).unwrap().start();
-
-            // Remove old suffix
-            let new_fname = &new_path.file_stem().unwrap().to_str().unwrap()[0..start - 2];
-
-            // Create new file name with new suffix
-            let fname = PathBuf::from(
-                format!("{} ({}).{}",
-                        new_fname,
-                        num + 1,
-                        extension.clone().to_str().unwrap()
-                )
-            );
+    // Output format may come from the profile
+    output_format(args.output_format);
+    status!("Using profile {} from {}", name, path.display());
+
+    Ok(())
+}
 
-            new_path = PathBuf::from(target_dir.clone());
-            new_path = new_path.join(fname.clone());
+fn run(args: RunArgs) -> Result<RunOutcome, Error> {
+    let mut sort_args = args.sort;
+    use_profile(&mut sort_args, &args.profile, args.config)?;
+    sort(sort_args)
+}
+
+fn watch(args: WatchArgs) -> Result<RunOutcome, Error> {
+    let mut sort_args = args.sort;
+
+    if let Some(name) = &args.profile {
+        use_profile(&mut sort_args, name, args.config)?;
+    }
+
+    let mut plan_args = sort_args.plan;
+    check_dirs(&plan_args);
+
+    // Events are reported with absolute paths
+    plan_args.target = plan_args.target.map(|t| t.canonicalize()).transpose()?;
+    plan_args.paths = plan_args.paths.iter().map(|p| p.canonicalize()).collect::<Result<_, _>>()?;
+
+    let target = plan_args.target.clone().unwrap_or_default();
+    let options = plan_options(&plan_args, &target);
+    let format = output_format(sort_args.output_format);
+    let seconds = |s: f64| match Duration::try_from_secs_f64(s) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("invalid time {}: {}", s, e);
+            exit(EXIT_FATAL);
         }
+    };
+
+    let watch_options = WatchOptions {
+        debounce: seconds(args.debounce),
+        stable_for: seconds(args.stable_for),
+    };
+
+    lajittelia::watch(
+        &target,
+        &plan_args.paths,
+        &options,
+        &watch_options,
+        || load_aliases(&plan_args, &target),
+        |event| match event {
+            WatchEvent::Started => {
+                status!("Watching {} for files to sort into {}",
+                         plan_args.paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", "),
+                         target.display());
+            }
+            WatchEvent::Reloaded(index) => status!("Target directories changed, {} aliases", index.len()),
+            WatchEvent::ReloadFailed(e) => eprintln!("error: reading aliases failed, using previous aliases: {:?}", e),
+            WatchEvent::Planned(plan) => {
+                if let Err(e) = execute_plan(plan, sort_args.move_files, sort_args.journal.clone(), sort_args.no_backup, format) {
+                    eprintln!("error: {}", e);
+                }
+            }
+        },
+    )?;
+
+    Ok(RunOutcome::Success)
+}
+
+fn write_plan(args: PlanCommandArgs) -> Result<RunOutcome, Error> {
+    let plan = build_plan(args.plan)?;
+    plan.save(&args.output)?;
+
+    for e in &plan.errors {
+        eprintln!("error: {}", e);
     }
 
-    Ok(new_path)
+    println!("Wrote plan to {}: {} to be moved, {} multiple matches, {} to review, {} unmatched, {} skipped, {} failed",
+             args.output.display(),
+             plan.moves.len(),
+             plan.multiple_matches.len(),
+             plan.review.len(),
+             plan.unmatched.len(),
+             plan.skipped.len(),
+             plan.errors.len(),
+    );
+
+    Ok(Summary::from_plan(&plan).outcome())
 }
 
-// CLI arguments
-// See: https://docs.rs/clap/latest/clap/
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct CLIArgs {
-    #[clap(short = 't', long, required = true,
-    help = "Target directory for sorted files")]
-    target: PathBuf,
+fn apply(args: ApplyArgs) -> Result<RunOutcome, Error> {
+    let plan = SortPlan::load(&args.plan)?;
+    let format = output_format(args.output_format);
+    status!("Applying plan {}", args.plan.display());
+    execute_plan(&plan, args.move_files, args.journal, args.no_backup, format).map(|s| s.outcome())
+}
 
-    #[clap(short = 'Y', long, help = "Move files? If enabled, files are actually moved")]
-    move_files: bool,
+fn undo(args: UndoArgs) -> Result<RunOutcome, Error> {
+    let path = journal_path(args.journal);
+    // Nothing sorted yet
+    let entries = if path.exists() { read_journal(&path)? } else { Vec::new() };
+    let runs = journal_runs(&entries);
 
-    #[clap(
-    help = "Path(s) to scan for files to be sorted",
-    required = true)]
-    paths: Vec<PathBuf>,
+    if args.list && args.run.is_none() {
+        if runs.is_empty() {
+            println!("No runs in journal {}", path.display());
+        }
+
+        for (run_id, count) in runs {
+            println!("{} ({} moved)", run_id, count);
+        }
+
+        return Ok(RunOutcome::Success);
+    }
+
+    let run_id = match args.run.or_else(|| runs.last().map(|(id, _)| id.clone())) {
+        Some(id) => id,
+        None => {
+            eprintln!("no runs in journal {}", path.display());
+            exit(EXIT_FATAL);
+        }
+    };
+
+    if !runs.iter().any(|(id, _)| *id == run_id) {
+        eprintln!("run {} not found in journal {}", run_id, path.display());
+        exit(EXIT_FATAL);
+    }
+
+    let items = plan_undo(&entries, &run_id, &args.entries);
+
+    if args.list {
+        for item in items.iter().rev() {
+            println!("{}: {} -> {}", item.index, item.entry.source.display(), item.entry.destination.display());
+        }
+
+        return Ok(RunOutcome::Success);
+    }
+
+    let journal = Journal::open(&path)?;
+
+    println!("Undoing run {}", run_id);
+
+    let mut outcome = RunOutcome::Success;
+
+    for item in items {
+        let (from, to) = (&item.entry.destination, &item.entry.source);
+
+        if let Some(blocked) = item.blocked {
+            eprintln!("Not moving {} back to {}: {}", from.display(), to.display(), blocked);
+            outcome = RunOutcome::SomeFailed;
+            continue;
+        }
+
+        if !args.move_files {
+            match item.entry.action {
+                JournalAction::Move => println!("Not moving {} back to {}", from.display(), to.display()),
+                JournalAction::Delete => println!("Not copying {} back to {}", from.display(), to.display()),
+                _ => println!("Not removing {}", from.display()),
+            }
+
+            continue;
+        }
+
+        match undo_move(&journal, &item, true) {
+            Ok(()) => {
+                match item.entry.action {
+                    JournalAction::Move => println!("Moved {} back to {}", from.display(), to.display()),
+                    JournalAction::Delete => println!("Copied {} back to {}", from.display(), to.display()),
+                    _ => println!("Removed {}", from.display()),
+                }
+
+                if item.entry.replaced.is_some() {
+                    println!("Restored the replaced {}", from.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {}: {}", from.display(), e);
+                outcome = RunOutcome::SomeFailed;
+            }
+        }
+    }
+
+    Ok(outcome)
 }
 
-fn main() -> Result<(), Error> {
-    let args: CLIArgs = CLIArgs::parse();
+fn check(args: CheckArgs) -> Result<RunOutcome, Error> {
+    let index = match (args.target_depth, args.nested) {
+        (Some(depth), _) => AliasIndex::from_target_nested(&args.target, Some(depth))?,
+        (None, true) => AliasIndex::from_target_nested(&args.target, None)?,
+        (None, false) => AliasIndex::from_target(&args.target)?,
+    };
+
+    let problems = check_aliases(&index);
 
-    if !args.target.is_dir() {
-        eprintln!("not a directory: {}", args.target.display());
-        exit(1);
+    for problem in &problems {
+        println!("{}", problem);
     }
 
-    if args.paths.is_empty() {
-        eprintln!("no directories given to be scanned");
-        exit(1);
+    println!("Checked {} aliases in {}: {} problems", index.origins().len(), args.target.display(), problems.len());
+
+    if problems.is_empty() {
+        Ok(RunOutcome::Success)
+    } else {
+        Ok(RunOutcome::SomeAmbiguous)
     }
+}
 
-    // Only directories as sources
-    for p in args.paths.clone() {
-        if !p.is_dir() {
-            eprintln!("not a directory: {}", p.display());
-            exit(1);
+// "Foo Bar" or "Music/Foo Bar" in nested targets
+fn relative_dir(dir: &Path, target: &Path) -> String {
+    dir.strip_prefix(target).unwrap_or(dir).display().to_string()
+}
+
+fn describe_hit(hit: &Hit, target: &Path) -> String {
+    let mut s = format!("{:?} in {} at {}..{}", hit.alias, relative_dir(&hit.target_dir, target), hit.start, hit.end);
+
+    if hit.score < 100 {
+        s.push_str(&format!(", fuzzy score {}", hit.score));
+    }
+
+    s
+}
+
+// What sorting would do with the name, planned as when sorting
+fn print_decision(path: &Path, is_dir: bool, hits: Vec<Hit>, matcher: &Matcher, options: &PlanOptions) {
+    let plan = SortPlan::build_matched(matcher, path, is_dir, hits, options);
+
+    for m in &plan.moves {
+        let what = match m.action {
+            Action::Move if m.alias.is_empty() => "move to fallback directory",
+            Action::Move => "move to",
+            Action::Copy => "copy to",
+            Action::Link => "link to",
+            Action::Delete => "delete, identical to",
+        };
+        let renamed = if m.destination.file_name() != path.file_name() { " (exists, renamed)" } else { "" };
+
+        println!("Decision: {} {}{}{}", what, m.destination.display(), renamed, notes(m));
+    }
+
+    if !plan.multiple_matches.is_empty() {
+        println!("Decision: not moved, matches multiple aliases (see --multiple)");
+    }
+
+    for r in &plan.review {
+        let review_below = matcher.fuzzy().map(|f| f.review_below).unwrap_or(0);
+        println!("Decision: left for review, fuzzy score {} of {:?} is below {}", r.score, r.alias, review_below);
+    }
+
+    for s in &plan.skipped {
+        println!("Decision: skipped, {}", s.reason);
+    }
+
+    for e in &plan.errors {
+        // Keep-newer, keep-larger and delete-identical compare with the existing destination
+        let hint = if path.symlink_metadata().is_err() { ", the collision policy needs an existing file" } else { "" };
+        println!("Decision: planning failed: {}{}", e.message, hint);
+    }
+}
+
+fn explain_name(path: &Path, is_dir: bool, matcher: &Matcher, options: &PlanOptions, target: &Path) {
+    // As in `Matcher::find`, directories are matched with the whole name
+    let (name, extension) = if is_dir {
+        (path.file_name(), None)
+    } else {
+        (path.file_stem(), Some(path.extension().map(|e| e.to_string_lossy()).unwrap_or_default()))
+    };
+
+    let name = match name {
+        Some(n) => n.to_string_lossy(),
+        None => {
+            println!("{}: no file name", path.display());
+            return;
         }
+    };
+
+    match &extension {
+        Some(ext) if !ext.is_empty() => println!("{}: file, matched without extension {:?}", path.display(), ext),
+        Some(_) => println!("{}: file without extension", path.display()),
+        None => println!("{}: directory, matched with the whole name", path.display()),
     }
 
-    println!("Using {} as sorting target directory", args.target.display());
+    let explanation = matcher.explain(&name, extension.as_deref());
 
-    let aliases = generate_aliases(&args.target)?;
+    println!("Normalization:");
 
-    if aliases.is_empty() {
-        eprintln!("target directory {} is empty?", args.target.display());
-        exit(1);
+    for (step, text) in &explanation.steps {
+        println!("  {:<14} {:?}", step, text);
     }
 
-    println!("Finding matches...");
+    let normalized = explanation.steps.last().map(|(_, n)| n.as_str()).unwrap_or_default();
 
-    let (candidates, multiple_matches) = search_candidates(aliases.clone(), args.paths)?;
+    let hits: Vec<Hit> = match explanation.result {
+        Match::None => Vec::new(),
+        Match::Single(hit) => vec![hit],
+        Match::Multiple(hits) => hits,
+    };
 
-    if !candidates.is_empty() {
-        println!("Matches:");
+    println!("Aliases tried, longest first:");
 
-        for (candidate, alias) in candidates.clone() {
-            let target_dir = aliases[&alias.clone()].to_owned();
-            let new_path = rename_destination(candidate.clone(), target_dir)?;
+    let threshold = matcher.fuzzy().map(|f| f.threshold).unwrap_or(u8::MAX);
+    let mut marks = Vec::new();
 
-            if args.move_files {
-                // Actually move
-                match fs::rename(candidate.clone(), new_path.clone()) {
-                    Ok(()) => {
-                        println!("Moved {} to {}", candidate.display(), new_path.display());
-                    }
-                    Err(e) => eprintln!("error: {:?}", e),
-                }
-            } else {
-                println!("Not moving {} to {}", candidate.display(), new_path.display());
+    for trial in &explanation.trials {
+        let is_hit = hits.iter().any(|h| h.alias == trial.alias && h.target_dir == trial.target_dir);
+        let fuzzy_found = trial.fuzzy.is_some_and(|(score, _, _)| score >= threshold);
+
+        let mark = if trial.spans.is_empty() && !fuzzy_found {
+            "-"
+        } else if !trial.accepted {
+            "excluded"
+        } else if is_hit && fuzzy_found {
+            "fuzzy"
+        } else if is_hit {
+            "hit"
+        } else {
+            "dropped"
+        };
+
+        let mut spans = trial.spans.iter()
+            .map(|&(start, end)| format!("{}..{} {:?}", start, end, &normalized[start..end]))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // Scores below the threshold are shown as well, to see how close they were
+        if let Some((score, start, end)) = trial.fuzzy {
+            spans = format!("score {} at {}..{} {:?}", score, start, end, &normalized[start..end]);
+        }
+
+        let pattern = if trial.glob {
+            format!("glob {:?}", trial.pattern)
+        } else {
+            format!("/{}/", trial.pattern)
+        };
+
+        println!("  {:<8} {:?} {} -> {}{}{}", mark, trial.alias, pattern, relative_dir(&trial.target_dir, target),
+                 if spans.is_empty() { "" } else { ": " }, spans);
+        marks.push(mark);
+    }
+
+    if marks.contains(&"excluded") {
+        println!("  (excluded: ruled out by negative aliases or extension filters of the directory)");
+    }
+
+    if marks.contains(&"dropped") {
+        println!("  (dropped: inside a longer alias, in a parent of a deeper matching directory or a lower fuzzy score)");
+    }
+
+    match hits.len() {
+        0 => println!("Result: no match"),
+        1 => println!("Result: single match {}", describe_hit(&hits[0], target)),
+        _ => {
+            println!("Result: {} matches", hits.len());
+
+            for hit in &hits {
+                println!("  {}", describe_hit(hit, target));
             }
         }
+    }
 
-        println!();
+    if hits.is_empty() {
+        println!("Decision: unmatched, not moved");
+    } else {
+        print_decision(path, is_dir, hits, matcher, options);
+    }
+}
+
+fn explain(args: ExplainArgs) -> Result<RunOutcome, Error> {
+    let args = args.plan;
+    let target = check_target(&args);
+    let (_, matcher) = load_aliases(&args, &target)?;
+    let options = plan_options(&args, &target);
+
+    for (i, path) in args.paths.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        explain_name(path, args.directories || path.is_dir(), &matcher, &options, &target);
     }
 
-    if !multiple_matches.is_empty() {
-        println!("Multiple matches (not moved):");
+    Ok(RunOutcome::Success)
+}
+
+fn main() {
+    let args: CLIArgs = CLIArgs::parse();
 
-        for p in multiple_matches {
-            println!("{}", p.display())
+    let result = match args.command {
+        Some(Command::Plan(plan_args)) => write_plan(plan_args),
+        Some(Command::Apply(apply_args)) => apply(apply_args),
+        Some(Command::Undo(undo_args)) => undo(undo_args),
+        Some(Command::Watch(watch_args)) => watch(watch_args),
+        Some(Command::Run(run_args)) => run(run_args),
+        Some(Command::Check(check_args)) => check(check_args),
+        Some(Command::Explain(explain_args)) => explain(explain_args),
+        None => sort(args.sort),
+    };
+
+    let code = match result {
+        Ok(RunOutcome::Success) => 0,
+        Ok(RunOutcome::SomeAmbiguous) => EXIT_SOME_AMBIGUOUS,
+        Ok(RunOutcome::SomeFailed) => EXIT_SOME_FAILED,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            EXIT_FATAL
         }
+    };
 
-        println!();
+    exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sort arguments of `lajittelia run test <cli>` with `profile` applied
+    fn profile_args(cli: &[&str], profile: &Profile) -> Result<SortArgs, Error> {
+        let mut args = match CLIArgs::try_parse_from(["lajittelia", "run", "test"].iter().chain(cli)).unwrap().command {
+            Some(Command::Run(run)) => run.sort,
+            other => panic!("expected the run command, got {:?}", other),
+        };
+
+        apply_profile(&mut args, "test", profile)?;
+        Ok(args)
     }
 
-    Ok(())
+    #[test]
+    fn command_line_lists_replace_profile_lists() {
+        let profile = Profile {
+            sources: vec![PathBuf::from("/downloads")],
+            exclude: vec!["*.nfo".to_string()],
+            extensions: vec!["mp3".to_string()],
+            ..Profile::default()
+        };
+
+        let args = profile_args(&["--exclude", "*.txt", "/other"], &profile).unwrap();
+        assert_eq!(args.plan.exclude, vec!["*.txt"]);
+        assert_eq!(args.plan.paths, vec![PathBuf::from("/other")]);
+        assert_eq!(args.plan.extensions, vec!["mp3"]);
+
+        let args = profile_args(&[], &profile).unwrap();
+        assert_eq!(args.plan.exclude, vec!["*.nfo"]);
+        assert_eq!(args.plan.paths, vec![PathBuf::from("/downloads")]);
+    }
+
+    #[test]
+    fn no_switches_turn_off_profile_switches() {
+        let profile = Profile {
+            fuzzy: true,
+            nested: true,
+            target_depth: Some(2),
+            recursive: true,
+            max_depth: Some(3),
+            directories: true,
+            ..Profile::default()
+        };
+
+        let args = profile_args(&[], &profile).unwrap();
+        assert!(args.plan.fuzzy && args.plan.nested && args.plan.recursive && args.plan.directories);
+        assert_eq!((args.plan.target_depth, args.plan.max_depth), (Some(2), Some(3)));
+
+        let args = profile_args(&["--no-fuzzy", "--no-nested", "--no-recursive", "--no-directories"], &profile).unwrap();
+        assert!(!args.plan.fuzzy && !args.plan.nested && !args.plan.recursive && !args.plan.directories);
+        assert_eq!((args.plan.target_depth, args.plan.max_depth), (None, None));
+
+        // The last one given wins
+        assert!(profile_args(&["--no-fuzzy", "--fuzzy"], &profile).unwrap().plan.fuzzy);
+        assert!(!profile_args(&["--fuzzy", "--no-fuzzy"], &profile).unwrap().plan.fuzzy);
+    }
+
+    #[test]
+    fn command_line_values_override_profile() {
+        let profile = Profile {
+            target: Some(PathBuf::from("/music")),
+            collision: Some("keep-newer".to_string()),
+            min_age: Some(60),
+            ..Profile::default()
+        };
+
+        let args = profile_args(&["-t", "/other", "--collision", "skip"], &profile).unwrap();
+        assert_eq!(args.plan.target, Some(PathBuf::from("/other")));
+        assert!(matches!(args.plan.collision, Some(CollisionArg::Skip)));
+        assert_eq!(args.plan.min_age, Some(60));
+
+        let args = profile_args(&[], &profile).unwrap();
+        assert_eq!(args.plan.target, Some(PathBuf::from("/music")));
+        assert!(matches!(args.plan.collision, Some(CollisionArg::KeepNewer)));
+    }
+
+    #[test]
+    fn invalid_profile_policy_fails() {
+        let profile = Profile {
+            multiple: Some("loudest".to_string()),
+            ..Profile::default()
+        };
+
+        let e = profile_args(&[], &profile).unwrap_err();
+        assert!(e.to_string().starts_with("profile test: invalid multiple \"loudest\""), "{}", e);
+        // Not read when given on the command line
+        assert!(profile_args(&["--multiple", "longest"], &profile).is_ok());
+    }
 }
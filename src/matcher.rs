@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use convert_case::{Case, Casing};
+use rayon::prelude::*;
+use globset::GlobMatcher;
+use regex::{escape, Regex};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::alias::{glob_rule, regex_rule, AliasIndex, DirRules};
+
+/// Alias found in a normalized name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hit {
+    pub alias: String,
+    #[serde(with = "crate::os_name::path")]
+    pub target_dir: PathBuf,
+    // Byte span of the first occurrence in the normalized name, glob aliases
+    // span the whole name
+    pub start: usize,
+    pub end: usize,
+    // Confidence in percent, 100 for exact matches, see `FuzzyOptions`
+    pub score: u8,
+}
+
+impl Hit {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Result of matching one name against all aliases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Match {
+    None,
+    Single(Hit),
+    // Longest alias first
+    Multiple(Vec<Hit>),
+}
+
+/// One alias tried by `Matcher::explain`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasTrial {
+    pub alias: String,
+    // Regular expression of plain and regex aliases, pattern of glob aliases
+    pub pattern: String,
+    // Glob alias, matched against the name as is instead of the normalized name
+    pub glob: bool,
+    pub target_dir: PathBuf,
+    // Byte spans of all occurrences in the normalized name, empty if not found
+    pub spans: Vec<(usize, usize)>,
+    // False if negative aliases or extension filters of the target directory
+    // (or its parents) rule the name out
+    pub accepted: bool,
+    // Fuzzy score and span of the closest words in the normalized name, for
+    // plain aliases when fuzzy matching is used (no exact hits)
+    pub fuzzy: Option<(u8, usize, usize)>,
+}
+
+/// How a name was matched, see `Matcher::explain`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    // Step and the name after it, from the name as given to the normalized name
+    pub steps: Vec<(&'static str, String)>,
+    // Every alias, longest first
+    pub trials: Vec<AliasTrial>,
+    // Same as `find_name`
+    pub result: Match,
+}
+
+/// Typo tolerant matching of plain aliases, used only when a name has no
+/// exact alias hits. Score is 100 * (1 - edit distance / length) of the
+/// alias and the closest run of words in the name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuzzyOptions {
+    // Minimum score (0-100) to count as a hit
+    pub threshold: u8,
+    // Hits scoring below this are left for review instead of being moved
+    pub review_below: u8,
+}
+
+impl Default for FuzzyOptions {
+    fn default() -> Self {
+        FuzzyOptions {
+            threshold: 80,
+            review_below: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    // Plain aliases, matched against the normalized name
+    Regex(Regex),
+    // Regex aliases, matched against the normalized name or, if that fails,
+    // against the name before lowercasing, which keeps "S01E02" together
+    // ("s 01 e 02" when normalized)
+    Rule(Regex),
+    // Matched against the name as is
+    Glob(GlobMatcher),
+}
+
+#[derive(Debug, Clone)]
+struct CompiledAlias {
+    alias: String,
+    pattern: Pattern,
+    dir: PathBuf,
+    // Normalized plain alias for fuzzy matching, None for regex and glob aliases
+    fuzzy: Option<String>,
+}
+
+impl CompiledAlias {
+    // Spans of all occurrences in the normalized name. Glob aliases and
+    // regex aliases matching only the name before lowercasing span the
+    // whole name.
+    fn find_iter(&self, name: &str, normalized: &str) -> Vec<(usize, usize)> {
+        match &self.pattern {
+            Pattern::Regex(re) => spans(re, normalized),
+            Pattern::Rule(re) => {
+                let found = spans(re, normalized);
+
+                if found.is_empty() && re.is_match(&Matcher::before_lowercase(name)) {
+                    vec![(0, normalized.len())]
+                } else {
+                    found
+                }
+            }
+            Pattern::Glob(glob) if glob.is_match(name) => vec![(0, normalized.len())],
+            Pattern::Glob(_) => vec![],
+        }
+    }
+
+    // Do the spans of a hit stand for the whole name rather than where the
+    // alias was found
+    fn spans_whole_name(&self, normalized: &str) -> bool {
+        match &self.pattern {
+            Pattern::Regex(_) => false,
+            Pattern::Rule(re) => !re.is_match(normalized),
+            Pattern::Glob(_) => true,
+        }
+    }
+
+    fn pattern_text(&self) -> String {
+        match &self.pattern {
+            Pattern::Regex(re) | Pattern::Rule(re) => re.as_str().to_string(),
+            Pattern::Glob(glob) => glob.glob().glob().to_string(),
+        }
+    }
+}
+
+/// Compiled alias regular expressions
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    // Longest alias first
+    aliases: Vec<CompiledAlias>,
+    // Negative aliases and extension filters of target directories
+    excludes: HashMap<PathBuf, Vec<Regex>>,
+    rules: HashMap<PathBuf, DirRules>,
+    fuzzy: Option<FuzzyOptions>,
+    // Remove diacritics from names and aliases
+    fold: bool,
+}
+
+fn trim_str(s: &str) -> String {
+    let remove: &[_] = &['_', '.', '-', ' '];
+    s.trim_matches(remove).to_string()
+}
+
+/// Remove diacritics: "Motörhead" -> "Motorhead", "Åänekoski" -> "Aanekoski"
+pub fn fold_accents(s: &str) -> String {
+    s.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        // Letters without a decomposed form
+        .map(|c| match c {
+            'ø' => 'o',
+            'Ø' => 'O',
+            'đ' => 'd',
+            'Đ' => 'D',
+            'ł' => 'l',
+            'Ł' => 'L',
+            c => c,
+        })
+        .nfc()
+        .collect()
+}
+
+// Levenshtein distance in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// Byte spans of space separated words
+fn words(s: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+
+    for (i, c) in s.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(st)) => {
+                spans.push((st, i));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+
+    if let Some(st) = start {
+        spans.push((st, s.len()));
+    }
+
+    spans
+}
+
+// Best score and span of `alias` compared with every run of as many words in `normalized`
+fn fuzzy_score(alias: &str, normalized: &str, name_words: &[(usize, usize)]) -> Option<(u8, usize, usize)> {
+    let count = alias.split_whitespace().count();
+    let alias_len = alias.chars().count();
+
+    if count == 0 || count > name_words.len() {
+        return None;
+    }
+
+    name_words.windows(count)
+        .map(|w| {
+            let (start, end) = (w[0].0, w[count - 1].1);
+            let window = &normalized[start..end];
+            let longest = alias_len.max(window.chars().count());
+            let distance = edit_distance(alias, window);
+            let score = 100 * longest.saturating_sub(distance) / longest;
+            (score as u8, start, end)
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)))
+}
+
+fn spans(re: &Regex, text: &str) -> Vec<(usize, usize)> {
+    re.find_iter(text)
+        .map(|m| (m.start(), m.end()))
+        .collect()
+}
+
+// Alias must have word boundaries
+fn word_regex(alias: &str) -> Result<Regex, Error> {
+    Regex::new(&format!(r"\b{}\b", escape(alias)))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+impl Matcher {
+    pub fn new(index: &AliasIndex) -> Result<Matcher, Error> {
+        Matcher::with_folding(index, false)
+    }
+
+    /// Matcher which removes diacritics (see `fold_accents`) from both
+    /// aliases and names if `fold` is set, so that "Motorhead" matches
+    /// "Motörhead" and the other way around
+    pub fn with_folding(index: &AliasIndex, fold: bool) -> Result<Matcher, Error> {
+        let text = |s: &str| Matcher::fold_text(s, fold);
+        let mut aliases = Vec::new();
+        let mut excludes = HashMap::new();
+        let mut rules = HashMap::new();
+
+        for alias in index.sorted_aliases() {
+            let dir = match index.get(&alias) {
+                Some(d) => d.to_path_buf(),
+                None => continue,
+            };
+
+            let folded = text(&alias);
+
+            aliases.push(CompiledAlias {
+                pattern: Pattern::Regex(word_regex(&folded)?),
+                fuzzy: Some(text(&Matcher::normalize(&folded))),
+                alias,
+                dir,
+            });
+        }
+
+        for (dir, dir_rules) in index.iter_rules() {
+            // Rules were validated when they were added to the index
+            for pattern in &dir_rules.regex {
+                aliases.push(CompiledAlias {
+                    alias: pattern.clone(),
+                    pattern: Pattern::Rule(regex_rule(&text(pattern)).map_err(Error::other)?),
+                    dir: dir.to_path_buf(),
+                    fuzzy: None,
+                });
+            }
+
+            for pattern in &dir_rules.glob {
+                aliases.push(CompiledAlias {
+                    alias: pattern.clone(),
+                    pattern: Pattern::Glob(glob_rule(&text(pattern)).map_err(Error::other)?),
+                    dir: dir.to_path_buf(),
+                    fuzzy: None,
+                });
+            }
+
+            let mut negative = Vec::new();
+
+            for exclude in &dir_rules.exclude {
+                negative.push(word_regex(&text(&exclude.trim().to_lowercase()))?);
+            }
+
+            excludes.insert(dir.to_path_buf(), negative);
+            rules.insert(dir.to_path_buf(), dir_rules.clone());
+        }
+
+        // Regex and glob aliases are ordered with plain aliases by pattern length
+        aliases.sort_by(|a, b|
+            b.alias.len().cmp(&a.alias.len()).then_with(|| a.alias.cmp(&b.alias))
+        );
+
+        Ok(Matcher {
+            aliases,
+            excludes,
+            rules,
+            fuzzy: None,
+            fold,
+        })
+    }
+
+    // Name or alias in NFC, without diacritics if `fold` is set
+    fn fold_text(s: &str, fold: bool) -> String {
+        if fold {
+            fold_accents(s)
+        } else {
+            s.nfc().collect()
+        }
+    }
+
+    /// Enable fuzzy matching for names without exact hits
+    pub fn with_fuzzy(self, fuzzy: FuzzyOptions) -> Matcher {
+        Matcher {
+            fuzzy: Some(fuzzy),
+            ..self
+        }
+    }
+
+    pub fn fuzzy(&self) -> Option<&FuzzyOptions> {
+        self.fuzzy.as_ref()
+    }
+
+    /// Normalize file stem for matching: "Hello_World.Foo" -> "hello world foo".
+    /// Unicode is normalized to NFC first, so that decomposed names (macOS)
+    /// match composed aliases.
+    pub fn normalize(stem: &str) -> String {
+        let composed: String = stem.nfc().collect();
+        let [_, _, (_, lower)] = Matcher::normalize_steps(&composed);
+        lower
+    }
+
+    // Name in NFC after the steps of `normalize` before lowercasing
+    fn before_lowercase(name: &str) -> String {
+        let composed: String = name.nfc().collect();
+        let [_, (_, spaced), _] = Matcher::normalize_steps(&composed);
+        spaced
+    }
+
+    // Steps of `normalize` after NFC with the name after each
+    fn normalize_steps(composed: &str) -> [(&'static str, String); 3] {
+        let trimmed = trim_str(composed);
+        let spaced = trimmed.replace('.', " ");
+        let lower = spaced.to_case(Case::Lower);
+
+        [("trim_str", trimmed), ("'.' to ' '", spaced), ("to_case lower", lower)]
+    }
+
+    // Can a name with `extension` (None for directories) be sorted into `dir`.
+    // Rules of parent directories in nested targets apply as well.
+    fn accepts(&self, dir: &Path, normalized: &str, extension: Option<&str>) -> bool {
+        dir.ancestors().all(|d| {
+            if let Some(rules) = self.rules.get(d) {
+                if !rules.accepts_extension(extension) {
+                    return false;
+                }
+            }
+
+            match self.excludes.get(d) {
+                Some(negative) => !negative.iter().any(|re| re.is_match(normalized)),
+                None => true,
+            }
+        })
+    }
+
+    // Hits with their index in `aliases`, longest first. Target directory
+    // rules are applied if `extension` is given.
+    fn hits(&self, name: &str, normalized: &str, extension: Option<Option<&str>>) -> Vec<(usize, Hit)> {
+        self.aliases
+            .par_iter()
+            .enumerate()
+            .filter(|(_, a)| match extension {
+                Some(ext) => self.accepts(&a.dir, normalized, ext),
+                None => true,
+            })
+            .filter_map(|(i, a)| {
+                a.find_iter(name, normalized).first().map(|&(start, end)| (i, Hit {
+                    alias: a.alias.clone(),
+                    target_dir: a.dir.clone(),
+                    start,
+                    end,
+                    score: 100,
+                }))
+            })
+            .collect()
+    }
+
+    // Fuzzy hits scoring at least the threshold, best score first
+    fn fuzzy_hits(&self, normalized: &str, extension: Option<&str>, fuzzy: &FuzzyOptions) -> Vec<Hit> {
+        let name_words = words(normalized);
+
+        let mut hits: Vec<Hit> = self.aliases
+            .par_iter()
+            .filter(|a| self.accepts(&a.dir, normalized, extension))
+            .filter_map(|a| {
+                let (score, start, end) = fuzzy_score(a.fuzzy.as_deref()?, normalized, &name_words)?;
+
+                if score < fuzzy.threshold {
+                    return None;
+                }
+
+                Some(Hit {
+                    alias: a.alias.clone(),
+                    target_dir: a.dir.clone(),
+                    start,
+                    end,
+                    score,
+                })
+            })
+            .collect();
+
+        hits.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| b.len().cmp(&a.len())));
+        hits
+    }
+
+    /// Aliases matching name (without extension), longest first. Negative
+    /// aliases and extension filters are not applied.
+    pub fn find_all(&self, name: &str) -> Vec<Hit> {
+        let name = Matcher::fold_text(name, self.fold);
+        self.hits(&name, &Matcher::normalize(&name), None)
+            .into_iter()
+            .map(|(_, h)| h)
+            .collect()
+    }
+
+    /// Like `find_all`, but negative aliases and extension filters (`extension`
+    /// is None for directories) are applied and aliases found only inside a
+    /// longer alias are left out: with "foo" and "foo fighters", "foo fighters
+    /// live" is a hit for "foo fighters" only, while "foo fighters and foo"
+    /// hits both. With fuzzy matching enabled and no exact hits, the best
+    /// scoring fuzzy hits are returned. Hits for a directory containing
+    /// another hit's directory (nested targets) are left out as well.
+    pub fn find_longest(&self, name: &str, extension: Option<&str>) -> Vec<Hit> {
+        let hits = self.find_unnested(name, extension);
+
+        // Deepest target directory wins
+        hits.iter()
+            .filter(|h| !hits.iter().any(|o| o.target_dir != h.target_dir && o.target_dir.starts_with(&h.target_dir)))
+            .cloned()
+            .collect()
+    }
+
+    // `find_longest` without leaving out parent directories
+    fn find_unnested(&self, name: &str, extension: Option<&str>) -> Vec<Hit> {
+        let name = Matcher::fold_text(name, self.fold);
+        let normalized = Matcher::normalize(&name);
+        let hits = self.hits(&name, &normalized, Some(extension));
+
+        if hits.is_empty() {
+            if let Some(fuzzy) = &self.fuzzy {
+                let fuzzy_hits = self.fuzzy_hits(&normalized, extension, fuzzy);
+                let best = fuzzy_hits.first().map(|h| h.score);
+
+                return fuzzy_hits.into_iter()
+                    .filter(|h| Some(h.score) == best)
+                    .collect();
+            }
+        }
+
+        // Every occurrence of every hit. Whole name spans of glob and regex
+        // aliases don't say where the alias is, so they contain nothing.
+        let spans: Vec<Vec<(usize, usize)>> = hits.iter()
+            .map(|(i, _)| self.aliases[*i].find_iter(&name, &normalized))
+            .collect();
+        let whole_name: Vec<bool> = hits.iter()
+            .map(|(i, _)| self.aliases[*i].spans_whole_name(&normalized))
+            .collect();
+
+        let contained = |i: usize| -> bool {
+            spans[i].iter().all(|&(start, end)| {
+                spans.iter().enumerate().any(|(j, other)| {
+                    j != i && !whole_name[j] && other.iter().any(|&(s, e)| {
+                        s <= start && end <= e && e - s > end - start
+                    })
+                })
+            })
+        };
+
+        hits.into_iter()
+            .enumerate()
+            .filter(|(i, _)| !contained(*i))
+            .map(|(_, (_, h))| h)
+            .collect()
+    }
+
+    /// Match file name (without extension) of `path`, directories are
+    /// matched with the whole name. Names which are not valid UTF-8 are
+    /// matched lossily, invalid bytes replaced with U+FFFD.
+    pub fn find(&self, path: &Path) -> Match {
+        let (name, extension) = if path.is_dir() {
+            (path.file_name(), None)
+        } else {
+            (path.file_stem(), Some(path.extension().map(|e| e.to_string_lossy()).unwrap_or_default()))
+        };
+
+        match name {
+            Some(s) => self.find_name(&s.to_string_lossy(), extension.as_deref()),
+            None => Match::None,
+        }
+    }
+
+    /// Match a name which is not yet normalized. `extension` is the file
+    /// extension ("" if none) or None for directories.
+    pub fn find_name(&self, name: &str, extension: Option<&str>) -> Match {
+        let modified = Matcher::normalize(name);
+
+        if modified.is_empty() {
+            return Match::None;
+        }
+
+        let mut alias_matches = self.find_longest(name, extension);
+
+        match alias_matches.len() {
+            0 => Match::None,
+            1 => Match::Single(alias_matches.remove(0)),
+            _ => Match::Multiple(alias_matches),
+        }
+    }
+
+    /// Every step of `find_name` for debugging aliases: normalization of
+    /// the name, each alias tried with its spans in the normalized name and
+    /// the result. The name doesn't need to exist.
+    pub fn explain(&self, name: &str, extension: Option<&str>) -> Explanation {
+        let folded = Matcher::fold_text(name, self.fold);
+        let mut steps = vec![
+            ("name", name.to_string()),
+            (if self.fold { "fold_accents" } else { "unicode nfc" }, folded.clone()),
+        ];
+        steps.extend(Matcher::normalize_steps(&folded));
+
+        let normalized = Matcher::normalize(&folded);
+
+        let mut trials: Vec<AliasTrial> = self.aliases.iter()
+            .map(|a| AliasTrial {
+                alias: a.alias.clone(),
+                pattern: a.pattern_text(),
+                glob: matches!(a.pattern, Pattern::Glob(_)),
+                target_dir: a.dir.clone(),
+                spans: a.find_iter(&folded, &normalized),
+                accepted: self.accepts(&a.dir, &normalized, extension),
+                fuzzy: None,
+            })
+            .collect();
+
+        // As in `find_unnested`
+        let exact = trials.iter().any(|t| t.accepted && !t.spans.is_empty());
+
+        if self.fuzzy.is_some() && !exact {
+            let name_words = words(&normalized);
+
+            for (trial, a) in trials.iter_mut().zip(&self.aliases) {
+                trial.fuzzy = a.fuzzy.as_deref().and_then(|f| fuzzy_score(f, &normalized, &name_words));
+            }
+        }
+
+        Explanation {
+            steps,
+            trials,
+            result: self.find_name(name, extension),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Matcher for (alias, target directory) pairs
+    fn matcher(aliases: &[(&str, &str)]) -> Matcher {
+        let mut index = AliasIndex::new();
+
+        for (alias, dir) in aliases {
+            index.insert(*alias, PathBuf::from(dir));
+        }
+
+        Matcher::new(&index).unwrap()
+    }
+
+    fn dirs(found: Match) -> Vec<PathBuf> {
+        match found {
+            Match::None => vec![],
+            Match::Single(hit) => vec![hit.target_dir],
+            Match::Multiple(hits) => hits.into_iter().map(|h| h.target_dir).collect(),
+        }
+    }
+
+    #[test]
+    fn contained_alias_is_dropped() {
+        let matcher = matcher(&[("foo", "Foo"), ("foo fighters", "Foo Fighters")]);
+
+        assert_eq!(dirs(matcher.find_name("Foo.Fighters - Live", Some("mp3"))), vec![PathBuf::from("Foo Fighters")]);
+        // Found outside the longer alias as well
+        assert_eq!(
+            dirs(matcher.find_name("foo fighters and foo", Some("mp3"))),
+            vec![PathBuf::from("Foo Fighters"), PathBuf::from("Foo")],
+        );
+    }
+
+    #[test]
+    fn overlapping_aliases_of_equal_length_stay_ambiguous() {
+        let matcher = matcher(&[("foo bar", "Foo Bar"), ("bar baz", "Bar Baz")]);
+
+        assert_eq!(
+            dirs(matcher.find_name("foo bar baz", Some("mp3"))),
+            vec![PathBuf::from("Bar Baz"), PathBuf::from("Foo Bar")],
+        );
+    }
+
+    #[test]
+    fn aliases_of_different_directories_stay_ambiguous() {
+        let matcher = matcher(&[("foo", "Foo"), ("bar", "Bar")]);
+
+        match matcher.find_name("foo - bar", Some("mp3")) {
+            Match::Multiple(hits) => {
+                let found: Vec<(&str, usize, usize)> = hits.iter().map(|h| (h.alias.as_str(), h.start, h.end)).collect();
+                assert_eq!(found, vec![("bar", 4, 7), ("foo", 0, 3)]);
+            }
+            other => panic!("expected multiple matches, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("metallica", "metalica"), 1);
+        assert_eq!(edit_distance("motörhead", "motorhead"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", "abc"), 0);
+    }
+
+    #[test]
+    fn fuzzy_score_of_closest_words() {
+        let name = "live metalica 1989";
+
+        assert_eq!(fuzzy_score("metallica", name, &words(name)), Some((88, 5, 13)));
+        assert_eq!(fuzzy_score("live metallica", name, &words(name)), Some((92, 0, 13)));
+        // More words than the name has
+        assert_eq!(fuzzy_score("a b c d", name, &words(name)), None);
+    }
+
+    #[test]
+    fn fuzzy_threshold_is_inclusive() {
+        let fuzzy = |threshold| matcher(&[("metallica", "Metallica")])
+            .with_fuzzy(FuzzyOptions { threshold, review_below: 0 });
+
+        match fuzzy(88).find_name("Metalica - Live", Some("mp3")) {
+            Match::Single(hit) => assert_eq!((hit.alias.as_str(), hit.score, hit.start, hit.end), ("metallica", 88, 0, 8)),
+            other => panic!("expected a fuzzy match, got {:?}", other),
+        }
+
+        assert_eq!(fuzzy(89).find_name("Metalica - Live", Some("mp3")), Match::None);
+        // Without fuzzy matching
+        assert_eq!(matcher(&[("metallica", "Metallica")]).find_name("Metalica", Some("mp3")), Match::None);
+    }
+
+    #[test]
+    fn exact_hit_beats_fuzzy() {
+        let matcher = matcher(&[("metallica", "Metallica"), ("live", "Live")])
+            .with_fuzzy(FuzzyOptions::default());
+
+        match matcher.find_name("Metalica - Live", Some("mp3")) {
+            Match::Single(hit) => assert_eq!((hit.target_dir, hit.score), (PathBuf::from("Live"), 100)),
+            other => panic!("expected the exact match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn whole_name_spans_contain_nothing() {
+        let mut index = AliasIndex::new();
+        index.insert("foo fighters", PathBuf::from("Foo Fighters"));
+        index.insert_rules(PathBuf::from("Live"), DirRules {
+            glob: vec!["*live*".to_string()],
+            ..DirRules::default()
+        }).unwrap();
+        index.insert_rules(PathBuf::from("Episodes"), DirRules {
+            regex: vec![r"s\d{2}e\d{2}".to_string()],
+            ..DirRules::default()
+        }).unwrap();
+        let matcher = Matcher::new(&index).unwrap();
+
+        assert_eq!(
+            dirs(matcher.find_name("foo fighters live", Some("mp3"))),
+            vec![PathBuf::from("Foo Fighters"), PathBuf::from("Live")],
+        );
+        // Regex alias found only before lowercasing
+        assert_eq!(
+            dirs(matcher.find_name("Foo.Fighters.S01E02", Some("mkv"))),
+            vec![PathBuf::from("Foo Fighters"), PathBuf::from("Episodes")],
+        );
+        // Plain alias spanning the whole name still contains shorter ones
+        let matcher = self::matcher(&[("foo fighters", "Foo Fighters"), ("foo", "Foo")]);
+        assert_eq!(dirs(matcher.find_name("foo fighters", Some("mp3"))), vec![PathBuf::from("Foo Fighters")]);
+    }
+}
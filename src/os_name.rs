@@ -0,0 +1,103 @@
+// File names as bytes, so that names which are not valid UTF-8 survive
+// renaming and plan and journal files unchanged
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(unix)]
+pub(crate) fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    // Names which are not valid Unicode are handled lossily
+    match name.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn name_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn name_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Path as a string, or as {"bytes": [...]} if it isn't valid UTF-8
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PathRepr {
+    Text(String),
+    Bytes { bytes: Vec<u8> },
+}
+
+impl From<&Path> for PathRepr {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(s) => PathRepr::Text(s.to_string()),
+            None => PathRepr::Bytes { bytes: name_bytes(path.as_os_str()).into_owned() },
+        }
+    }
+}
+
+impl From<PathRepr> for PathBuf {
+    fn from(repr: PathRepr) -> Self {
+        match repr {
+            PathRepr::Text(s) => PathBuf::from(s),
+            PathRepr::Bytes { bytes } => PathBuf::from(name_from_bytes(bytes)),
+        }
+    }
+}
+
+/// `#[serde(with = "os_name::path")]` for `PathBuf` fields
+pub(crate) mod path {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        PathRepr::from(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        PathRepr::deserialize(deserializer).map(PathBuf::from)
+    }
+}
+
+/// `#[serde(with = "os_name::paths")]` for `Vec<PathBuf>` fields
+pub(crate) mod paths {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        paths.iter()
+            .map(|p| PathRepr::from(p.as_path()))
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+        Vec::<PathRepr>::deserialize(deserializer)
+            .map(|v| v.into_iter().map(PathBuf::from).collect())
+    }
+}
+
+/// `#[serde(with = "os_name::option")]` for `Option<PathBuf>` fields
+pub(crate) mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+        path.as_deref().map(PathRepr::from).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+        Option::<PathRepr>::deserialize(deserializer).map(|p| p.map(PathBuf::from))
+    }
+}
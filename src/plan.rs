@@ -0,0 +1,592 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::alias::AliasIndex;
+use crate::busy::{check_busy, BusyOptions};
+use crate::destination::{rename_destination, RenameOptions};
+use crate::error::{FileError, Stage};
+use crate::matcher::{Hit, Match, Matcher};
+use crate::policy::{Collision, CollisionPolicy, MultipleMatchPolicy, Resolution};
+use crate::scan::{scan_sources, ScanOptions, SkipReason, Skipped};
+
+/// What is done to the source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Move,
+    // Source stays, see `MultipleMatchPolicy::CopyAll`
+    Copy,
+    // Hard link, see `MultipleMatchPolicy::LinkAll`
+    Link,
+    // Source is removed, an identical file is already at the destination,
+    // see `CollisionPolicy::DeleteIdentical`
+    Delete,
+}
+
+/// One file to be moved into a target directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedMove {
+    #[serde(with = "crate::os_name::path")]
+    pub source: PathBuf,
+    // Empty when moved to the fallback directory of multiple matches
+    pub alias: String,
+    #[serde(with = "crate::os_name::path")]
+    pub target_dir: PathBuf,
+    #[serde(with = "crate::os_name::path")]
+    pub destination: PathBuf,
+    // Whole directory is moved
+    pub is_dir: bool,
+    #[serde(default)]
+    pub action: Action,
+    // Match confidence in percent, below 100 for fuzzy matches
+    #[serde(default = "exact_score")]
+    pub score: u8,
+    // Existing destination is replaced, see `CollisionPolicy`
+    #[serde(default)]
+    pub replace: bool,
+}
+
+fn exact_score() -> u8 {
+    100
+}
+
+/// File matching more than one alias
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AmbiguousMatch {
+    #[serde(with = "crate::os_name::path")]
+    pub source: PathBuf,
+    pub aliases: Vec<String>,
+}
+
+/// Fuzzy match with too low confidence to be moved automatically
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReviewMatch {
+    #[serde(with = "crate::os_name::path")]
+    pub source: PathBuf,
+    pub alias: String,
+    #[serde(with = "crate::os_name::path")]
+    pub target_dir: PathBuf,
+    pub score: u8,
+}
+
+/// Files matched against the alias index and where they would be moved
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SortPlan {
+    pub moves: Vec<PlannedMove>,
+    // Matched more than one alias, not moved
+    pub multiple_matches: Vec<AmbiguousMatch>,
+    // Low confidence fuzzy matches, not moved
+    #[serde(default)]
+    pub review: Vec<ReviewMatch>,
+    // Didn't match any alias
+    #[serde(with = "crate::os_name::paths")]
+    pub unmatched: Vec<PathBuf>,
+    // Left out while scanning sources
+    pub skipped: Vec<Skipped>,
+    // Files and directories which couldn't be scanned or planned
+    #[serde(default)]
+    pub errors: Vec<FileError>,
+}
+
+// Plan file format version, increase on incompatible changes
+const PLAN_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    version: u32,
+    plan: SortPlan,
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+/// Options for building a `SortPlan`
+#[derive(Debug, Clone, Default)]
+pub struct PlanOptions {
+    pub scan: ScanOptions,
+    // Files still being written are skipped
+    pub busy: BusyOptions,
+    pub multiple: MultipleMatchPolicy,
+    pub collision: CollisionPolicy,
+    pub rename: RenameOptions,
+}
+
+// Adds planned moves while keeping track of destinations already taken
+struct Planner {
+    reserved: HashSet<PathBuf>,
+    collision: CollisionPolicy,
+    rename: RenameOptions,
+    plan: SortPlan,
+}
+
+impl Planner {
+    fn push(&mut self, source: &Path, alias: &str, target_dir: &Path, is_dir: bool, mut action: Action, score: u8) -> Result<(), Error> {
+        let mut destination = match source.file_name() {
+            Some(name) => target_dir.join(name),
+            None => return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("no file name: {}", source.display()),
+            )),
+        };
+
+        let mut replace = false;
+
+        // Collisions with files already in the target directory, names
+        // taken by this plan are always renamed
+        let collision = if self.reserved.contains(&destination) || destination.symlink_metadata().is_err() {
+            Collision::Rename
+        } else {
+            self.collision.resolve(source, &destination, action)?
+        };
+
+        match collision {
+            Collision::Rename => {
+                destination = match rename_destination(source, target_dir, &self.reserved, &self.rename) {
+                    Ok(d) => d,
+                    Err(e) if e.kind() == ErrorKind::InvalidFilename => {
+                        self.plan.skipped.push(Skipped {
+                            path: source.to_path_buf(),
+                            reason: SkipReason::NameTooLong,
+                        });
+
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+            }
+            Collision::Replace => replace = true,
+            Collision::Skip(reason) => {
+                self.plan.skipped.push(Skipped {
+                    path: source.to_path_buf(),
+                    reason,
+                });
+
+                return Ok(());
+            }
+            Collision::DeleteSource => action = Action::Delete,
+        }
+
+        self.reserved.insert(destination.clone());
+
+        self.plan.moves.push(PlannedMove {
+            source: source.to_path_buf(),
+            alias: alias.to_string(),
+            target_dir: target_dir.to_path_buf(),
+            destination,
+            is_dir,
+            action,
+            score,
+            replace,
+        });
+
+        Ok(())
+    }
+
+    fn push_hit(&mut self, source: &Path, hit: &Hit, is_dir: bool, action: Action) -> Result<(), Error> {
+        self.push(source, &hit.alias, &hit.target_dir, is_dir, action, hit.score)
+    }
+}
+
+impl Planner {
+    fn new(skipped: Vec<Skipped>, options: &PlanOptions) -> Planner {
+        Planner {
+            reserved: HashSet::new(),
+            collision: options.collision,
+            rename: options.rename.clone(),
+            plan: SortPlan {
+                skipped,
+                ..SortPlan::default()
+            },
+        }
+    }
+
+    // Match and resolve sorted `candidates`, failures are collected to
+    // `SortPlan::errors` and the other candidates are still planned
+    fn add_candidates(&mut self, matcher: &Matcher, candidates: Vec<PathBuf>, options: &PlanOptions) {
+        // Matched directories, their contents are handled as part of the directory
+        let mut units: HashSet<PathBuf> = HashSet::new();
+
+        for source in candidates {
+            if let Err(e) = self.add_candidate(matcher, source.clone(), &mut units, options) {
+                self.plan.errors.push(FileError::new(&source, Stage::Plan, &e));
+            }
+        }
+    }
+
+    fn add_candidate(&mut self, matcher: &Matcher, source: PathBuf, units: &mut HashSet<PathBuf>, options: &PlanOptions) -> Result<(), Error> {
+        if source.ancestors().skip(1).any(|a| units.contains(a)) {
+            return Ok(());
+        }
+
+        let is_dir = source.is_dir();
+
+        let hits = match matcher.find(&source) {
+            // Lossy view of the name didn't match
+            Match::None if source.file_name().is_some_and(|n| n.to_str().is_none()) => {
+                self.plan.skipped.push(Skipped {
+                    path: source,
+                    reason: SkipReason::InvalidUnicode,
+                });
+                return Ok(());
+            }
+            Match::None => {
+                self.plan.unmatched.push(source);
+                return Ok(());
+            }
+            Match::Single(hit) => vec![hit],
+            Match::Multiple(hits) => hits,
+        };
+
+        if is_dir {
+            units.insert(source.clone());
+        }
+
+        self.add_hits(matcher, source, is_dir, hits, options)
+    }
+
+    // Resolve multiple matches and collisions of `source` matching `hits`
+    fn add_hits(&mut self, matcher: &Matcher, source: PathBuf, is_dir: bool, hits: Vec<Hit>, options: &PlanOptions) -> Result<(), Error> {
+        if hits.is_empty() {
+            self.plan.unmatched.push(source);
+            return Ok(());
+        }
+
+        let resolution = if hits.len() == 1 {
+            Resolution::Single(hits[0].clone())
+        } else {
+            options.multiple.resolve(&hits)
+        };
+
+        // Low confidence fuzzy matches are left for review
+        let review_below = matcher.fuzzy().map(|f| f.review_below).unwrap_or(0);
+        let chosen: &[Hit] = match &resolution {
+            Resolution::Single(hit) => std::slice::from_ref(hit),
+            Resolution::All(all) => all,
+            _ => &[],
+        };
+
+        if let Some(hit) = chosen.iter().find(|h| h.score < review_below) {
+            self.plan.review.push(ReviewMatch {
+                source,
+                alias: hit.alias.clone(),
+                target_dir: hit.target_dir.clone(),
+                score: hit.score,
+            });
+            return Ok(());
+        }
+
+        match resolution {
+            Resolution::Unresolved => {
+                self.plan.multiple_matches.push(AmbiguousMatch {
+                    source,
+                    aliases: hits.into_iter().map(|h| h.alias).collect(),
+                });
+            }
+            Resolution::Single(hit) => {
+                self.push_hit(&source, &hit, is_dir, Action::Move)?;
+            }
+            Resolution::Fallback(dir) => {
+                self.push(&source, "", &dir, is_dir, Action::Move, exact_score())?;
+            }
+            Resolution::All(all) => {
+                // Directories can't be hard linked, they are copied instead
+                let action = match options.multiple {
+                    MultipleMatchPolicy::LinkAll if !is_dir => Action::Link,
+                    _ => Action::Copy,
+                };
+
+                // Copies and links are made before the source is moved
+                for hit in all.iter().skip(1) {
+                    self.push_hit(&source, hit, is_dir, action)?;
+                }
+
+                if let Some(hit) = all.first() {
+                    self.push_hit(&source, hit, is_dir, Action::Move)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Directories never scanned for sources
+fn excluded_dirs(index: &AliasIndex, options: &PlanOptions) -> Vec<PathBuf> {
+    let mut exclude = index.directories();
+    exclude.extend(options.multiple.fallback_dir().map(|d| d.to_path_buf()));
+    exclude
+}
+
+impl SortPlan {
+    /// Scan `sources` and plan moves for files (and directories, see
+    /// `ScanOptions::directories`) matching one alias, or multiple aliases
+    /// resolved with `PlanOptions::multiple`
+    pub fn build(
+        index: &AliasIndex,
+        matcher: &Matcher,
+        sources: &[PathBuf],
+        options: &PlanOptions,
+    ) -> Result<SortPlan, Error> {
+        let scan = scan_sources(sources, &options.scan, &excluded_dirs(index, options))?;
+        let mut skipped = scan.skipped;
+
+        // Parent directories are sorted before their contents
+        let mut candidates: Vec<PathBuf> = scan.directories;
+        candidates.extend(scan.files);
+        candidates.sort();
+
+        let (candidates, busy) = check_busy(candidates, &options.busy)?;
+        skipped.extend(busy);
+
+        let mut planner = Planner::new(skipped, options);
+        planner.plan.errors = scan.errors;
+        planner.add_candidates(matcher, candidates, options);
+
+        Ok(planner.plan)
+    }
+
+    /// Like `build`, but only given `paths` are planned without scanning,
+    /// for example files reported by a file system watcher. Paths which no
+    /// longer exist or are inside target directories are left out.
+    pub fn build_paths(
+        index: &AliasIndex,
+        matcher: &Matcher,
+        paths: &[PathBuf],
+        options: &PlanOptions,
+    ) -> Result<SortPlan, Error> {
+        let exclude = excluded_dirs(index, options);
+
+        let mut candidates: Vec<PathBuf> = paths.iter()
+            .filter(|p| p.symlink_metadata().is_ok())
+            .filter(|p| !exclude.iter().any(|d| p.starts_with(d)))
+            .cloned()
+            .collect();
+
+        candidates.sort();
+        candidates.dedup();
+
+        let (candidates, skipped) = check_busy(candidates, &options.busy)?;
+        let mut planner = Planner::new(skipped, options);
+
+        planner.add_candidates(matcher, candidates, options);
+
+        Ok(planner.plan)
+    }
+
+    /// Plan one `source` which matched `hits` (see `Matcher::find_name`) the
+    /// way `build` would, without scanning or checking if it is busy. The
+    /// source doesn't need to exist, unless the collision policy has to
+    /// compare it with an existing destination.
+    pub fn build_matched(
+        matcher: &Matcher,
+        source: &Path,
+        is_dir: bool,
+        hits: Vec<Hit>,
+        options: &PlanOptions,
+    ) -> SortPlan {
+        let mut planner = Planner::new(Vec::new(), options);
+
+        if let Err(e) = planner.add_hits(matcher, source.to_path_buf(), is_dir, hits, options) {
+            planner.plan.errors.push(FileError::new(source, Stage::Plan, &e));
+        }
+
+        planner.plan
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.multiple_matches.is_empty() && self.review.is_empty()
+    }
+
+    /// Write plan to file, TOML if the file name ends with ".toml" and JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let file = PlanFile {
+            version: PLAN_VERSION,
+            plan: self.clone(),
+        };
+
+        let data = if is_toml(path) {
+            toml::to_string_pretty(&file)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        } else {
+            serde_json::to_string_pretty(&file)?
+        };
+
+        fs::write(path, data)
+    }
+
+    /// Read plan written with `save`
+    pub fn load(path: &Path) -> Result<SortPlan, Error> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let invalid = |e: String| Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        );
+
+        let file: PlanFile = if is_toml(path) {
+            toml::from_str(&data).map_err(|e| invalid(e.to_string()))?
+        } else {
+            serde_json::from_str(&data).map_err(|e| invalid(e.to_string()))?
+        };
+
+        if file.version != PLAN_VERSION {
+            return Err(invalid(format!("unsupported plan version {}", file.version)));
+        }
+
+        Ok(file.plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::matcher::FuzzyOptions;
+    use crate::testdir::temp_dir;
+
+    // Plan "Metalica.mp3" against target directory "Metallica", scoring 88
+    fn plan_metalica(name: &str, review_below: u8) -> SortPlan {
+        let root = temp_dir(name);
+        let (target, source) = (root.join("target"), root.join("source"));
+        fs::create_dir_all(target.join("Metallica")).unwrap();
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("Metalica.mp3"), "").unwrap();
+
+        let index = AliasIndex::from_target(&target).unwrap();
+        let matcher = Matcher::new(&index).unwrap()
+            .with_fuzzy(FuzzyOptions { threshold: 80, review_below });
+        let plan = SortPlan::build(&index, &matcher, &[source], &PlanOptions::default()).unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+        plan
+    }
+
+    fn sample_plan(source: PathBuf) -> SortPlan {
+        SortPlan {
+            moves: vec![PlannedMove {
+                source: source.clone(),
+                alias: "foo".to_string(),
+                target_dir: PathBuf::from("/target/Foo"),
+                destination: PathBuf::from("/target/Foo/foo (1).mp3"),
+                is_dir: false,
+                action: Action::Link,
+                score: 85,
+                replace: true,
+            }],
+            multiple_matches: vec![AmbiguousMatch {
+                source: PathBuf::from("/source/foo bar.mp3"),
+                aliases: vec!["foo".to_string(), "bar".to_string()],
+            }],
+            review: vec![ReviewMatch {
+                source: PathBuf::from("/source/metalica.mp3"),
+                alias: "metallica".to_string(),
+                target_dir: PathBuf::from("/target/Metallica"),
+                score: 88,
+            }],
+            unmatched: vec![PathBuf::from("/source/else.mp3")],
+            skipped: vec![Skipped {
+                path: PathBuf::from("/source/foo.part"),
+                reason: SkipReason::PartialDownload,
+            }],
+            errors: vec![FileError {
+                path: PathBuf::from("/source/locked"),
+                stage: Stage::Scan,
+                message: "permission denied".to_string(),
+            }],
+        }
+    }
+
+    // Save and load `plan` as `name`
+    fn round_trip(plan: &SortPlan, dir: &Path, name: &str) -> SortPlan {
+        let path = dir.join(name);
+        plan.save(&path).unwrap();
+        SortPlan::load(&path).unwrap()
+    }
+
+    fn assert_same(a: &SortPlan, b: &SortPlan) {
+        assert_eq!(a.moves, b.moves);
+        assert_eq!(a.multiple_matches, b.multiple_matches);
+        assert_eq!(a.review, b.review);
+        assert_eq!(a.unmatched, b.unmatched);
+        assert_eq!(a.skipped, b.skipped);
+        assert_eq!(a.errors, b.errors);
+    }
+
+    #[test]
+    fn plan_round_trip() {
+        let dir = temp_dir("plan-round-trip");
+        let plan = sample_plan(PathBuf::from("/source/foo.mp3"));
+
+        assert_same(&plan, &round_trip(&plan, &dir, "plan.json"));
+        assert_same(&plan, &round_trip(&plan, &dir, "plan.TOML"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_plan_version_is_refused() {
+        let dir = temp_dir("plan-version");
+        let plan = sample_plan(PathBuf::from("/source/foo.mp3"));
+
+        for (name, from, to) in [("plan.json", "\"version\": 1", "\"version\": 2"), ("plan.toml", "version = 1", "version = 2")] {
+            let path = dir.join(name);
+            plan.save(&path).unwrap();
+            let data = fs::read_to_string(&path).unwrap();
+            assert!(data.contains(from));
+            fs::write(&path, data.replacen(from, to, 1)).unwrap();
+
+            let e = SortPlan::load(&path).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().contains("unsupported plan version 2"), "{}", e);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = temp_dir("plan-non-utf8");
+        let source = PathBuf::from(OsStr::from_bytes(b"/source/caf\xe9.mp3"));
+        let mut plan = sample_plan(source.clone());
+        plan.unmatched.push(source.clone());
+
+        for name in ["plan.json", "plan.toml"] {
+            let loaded = round_trip(&plan, &dir, name);
+            assert_same(&plan, &loaded);
+            assert_eq!(loaded.moves[0].source, source);
+        }
+
+        let data = fs::read_to_string(dir.join("plan.json")).unwrap();
+        assert!(data.contains("\"bytes\": ["), "{}", data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn low_fuzzy_score_goes_to_review() {
+        let plan = plan_metalica("plan-review", 90);
+
+        assert!(plan.moves.is_empty());
+        assert_eq!(plan.review.len(), 1);
+        assert_eq!((plan.review[0].alias.as_str(), plan.review[0].score), ("metallica", 88));
+    }
+
+    #[test]
+    fn review_below_is_exclusive() {
+        let plan = plan_metalica("plan-review-below", 88);
+
+        assert!(plan.review.is_empty());
+        assert_eq!(plan.moves.len(), 1);
+        assert_eq!(plan.moves[0].score, 88);
+        assert!(plan.moves[0].destination.ends_with("Metallica/Metalica.mp3"));
+
+        assert_eq!(plan_metalica("plan-review-above", 89).review.len(), 1);
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use crate::matcher::Hit;
+use crate::plan::Action;
+use crate::scan::SkipReason;
+use crate::transfer::identical;
+
+/// How files matching more than one alias are sorted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MultipleMatchPolicy {
+    // Leave the file where it is
+    #[default]
+    Skip,
+    // Longest alias wins
+    Longest,
+    // Alias appearing earliest in the name wins, longest of those if many start at the same position
+    Earliest,
+    // First target directory in the list wins
+    Priority(Vec<PathBuf>),
+    // Move to a separate directory
+    Fallback(PathBuf),
+    // Move to the first (longest alias) target directory and copy into the others
+    CopyAll,
+    // Move to the first (longest alias) target directory and hard link into
+    // the others, directories are copied as they can't be hard linked
+    LinkAll,
+}
+
+/// Where a file matching multiple aliases goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    // Can't decide, not moved
+    Unresolved,
+    Single(Hit),
+    Fallback(PathBuf),
+    // Move into the first, copy or link into the rest. One hit per target directory.
+    All(Vec<Hit>),
+}
+
+// The only hit with the longest match
+fn longest(hits: &[&Hit]) -> Option<Hit> {
+    let max = hits.iter().map(|h| h.len()).max()?;
+    let mut longest = hits.iter().filter(|h| h.len() == max);
+
+    match (longest.next(), longest.next()) {
+        (Some(h), None) => Some((*h).clone()),
+        _ => None,
+    }
+}
+
+impl MultipleMatchPolicy {
+    /// Resolve `hits` (longest alias first) of one name
+    pub fn resolve(&self, hits: &[Hit]) -> Resolution {
+        let found = |h: Option<Hit>| match h {
+            Some(h) => Resolution::Single(h),
+            None => Resolution::Unresolved,
+        };
+
+        match self {
+            MultipleMatchPolicy::Skip => Resolution::Unresolved,
+            MultipleMatchPolicy::Longest => {
+                found(longest(&hits.iter().collect::<Vec<_>>()))
+            }
+            MultipleMatchPolicy::Earliest => {
+                let first = match hits.iter().map(|h| h.start).min() {
+                    Some(f) => f,
+                    None => return Resolution::Unresolved,
+                };
+
+                found(longest(&hits.iter().filter(|h| h.start == first).collect::<Vec<_>>()))
+            }
+            MultipleMatchPolicy::Priority(dirs) => {
+                let hit = dirs.iter().find_map(|dir| {
+                    hits.iter().find(|h| h.target_dir == *dir)
+                });
+
+                found(hit.cloned())
+            }
+            MultipleMatchPolicy::Fallback(dir) => Resolution::Fallback(dir.clone()),
+            MultipleMatchPolicy::CopyAll | MultipleMatchPolicy::LinkAll => {
+                let mut dirs: Vec<&Path> = Vec::new();
+                let mut all: Vec<Hit> = Vec::new();
+
+                for h in hits {
+                    if !dirs.contains(&h.target_dir.as_path()) {
+                        dirs.push(&h.target_dir);
+                        all.push(h.clone());
+                    }
+                }
+
+                Resolution::All(all)
+            }
+        }
+    }
+
+    /// Directory which must not be scanned for sources
+    pub fn fallback_dir(&self) -> Option<&Path> {
+        match self {
+            MultipleMatchPolicy::Fallback(dir) => Some(dir),
+            _ => None,
+        }
+    }
+}
+
+/// What is done when a file with the same name already exists in the target directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    // Leave the source where it is
+    Skip,
+    // Replace the existing file
+    Overwrite,
+    // Add " (N)" suffix to the name
+    #[default]
+    Rename,
+    // Replace if the source was modified later, otherwise skip
+    KeepNewer,
+    // Replace if the source is larger, otherwise skip
+    KeepLarger,
+    // Delete the source if the existing file has the same size and
+    // checksum, otherwise rename
+    DeleteIdentical,
+}
+
+/// How one collision is handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Collision {
+    Rename,
+    Replace,
+    Skip(SkipReason),
+    // Source is identical to the existing file
+    DeleteSource,
+}
+
+impl CollisionPolicy {
+    /// Decide what to do with `source` when `existing` is in the way.
+    /// Directories can't be compared by size or content, they are renamed
+    /// with keep-larger and delete-identical. Skip, overwrite and rename
+    /// don't look at the files, the source doesn't need to exist for them.
+    pub fn resolve(&self, source: &Path, existing: &Path, action: Action) -> Result<Collision, Error> {
+        let simple = match self {
+            CollisionPolicy::Skip => Some(Collision::Skip(SkipReason::DestinationExists)),
+            CollisionPolicy::Overwrite => Some(Collision::Replace),
+            CollisionPolicy::Rename => Some(Collision::Rename),
+            _ => None,
+        };
+
+        if let Some(collision) = simple {
+            return Ok(collision);
+        }
+
+        let (source_meta, existing_meta) = (source.symlink_metadata()?, existing.symlink_metadata()?);
+        let files = source_meta.is_file() && existing_meta.is_file();
+
+        let collision = match self {
+            CollisionPolicy::KeepNewer => {
+                if source_meta.modified()? > existing_meta.modified()? {
+                    Collision::Replace
+                } else {
+                    Collision::Skip(SkipReason::DestinationNotOlder)
+                }
+            }
+            CollisionPolicy::KeepLarger if files => {
+                if source_meta.len() > existing_meta.len() {
+                    Collision::Replace
+                } else {
+                    Collision::Skip(SkipReason::DestinationNotSmaller)
+                }
+            }
+            CollisionPolicy::DeleteIdentical if files && identical(source, existing)? => {
+                match action {
+                    Action::Move => Collision::DeleteSource,
+                    // Source stays, nothing to copy or link
+                    _ => Collision::Skip(SkipReason::Identical),
+                }
+            }
+            _ => Collision::Rename,
+        };
+
+        Ok(collision)
+    }
+}
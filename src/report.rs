@@ -0,0 +1,412 @@
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FileError, Stage};
+use crate::executor::Outcome;
+use crate::plan::{Action, AmbiguousMatch, PlannedMove, ReviewMatch, SortPlan};
+use crate::scan::{SkipReason, Skipped};
+
+/// Version of the `Record` schema. Fields are only added within a version,
+/// never renamed or removed.
+pub const REPORT_VERSION: u32 = 1;
+
+/// Machine readable output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // One document {"version": 1, "records": [...]}
+    Json,
+    // One record per line
+    Ndjson,
+    // Header line and one record per line, candidates separated with ";"
+    Csv,
+}
+
+/// What happened to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    // Moved, copied, linked or deleted, see `Record::action`
+    Done,
+    // Would have been done without --move-files
+    DryRun,
+    Failed,
+    // Matched multiple aliases, see `Record::candidates`
+    Ambiguous,
+    // Low confidence fuzzy match
+    Review,
+    Unmatched,
+    // See `Record::reason`
+    Skipped,
+}
+
+/// One file in the output. Fields which don't apply are null.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub version: u32,
+    pub status: RecordStatus,
+    #[serde(with = "crate::os_name::path")]
+    pub source: PathBuf,
+    // Chosen alias
+    pub alias: Option<String>,
+    #[serde(with = "crate::os_name::option")]
+    pub target_dir: Option<PathBuf>,
+    #[serde(with = "crate::os_name::option")]
+    pub destination: Option<PathBuf>,
+    pub action: Option<Action>,
+    // All matching aliases of ambiguous files
+    pub candidates: Vec<String>,
+    pub score: Option<u8>,
+    pub reason: Option<SkipReason>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    fn new(status: RecordStatus, source: &Path) -> Record {
+        Record {
+            version: REPORT_VERSION,
+            status,
+            source: source.to_path_buf(),
+            alias: None,
+            target_dir: None,
+            destination: None,
+            action: None,
+            candidates: Vec::new(),
+            score: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    /// Planned move and how executing it went
+    pub fn from_move(m: &PlannedMove, outcome: &Outcome) -> Record {
+        let status = match outcome {
+            Outcome::Moved => RecordStatus::Done,
+            Outcome::NotMoved => RecordStatus::DryRun,
+            Outcome::Failed(_) => RecordStatus::Failed,
+        };
+
+        Record {
+            // Empty for the fallback directory of multiple matches
+            alias: Some(m.alias.clone()).filter(|a| !a.is_empty()),
+            target_dir: Some(m.target_dir.clone()),
+            destination: Some(m.destination.clone()),
+            action: Some(m.action),
+            score: Some(m.score),
+            error: match outcome {
+                Outcome::Failed(e) => Some(e.to_string()),
+                _ => None,
+            },
+            ..Record::new(status, &m.source)
+        }
+    }
+
+    pub fn from_ambiguous(m: &AmbiguousMatch) -> Record {
+        Record {
+            candidates: m.aliases.clone(),
+            ..Record::new(RecordStatus::Ambiguous, &m.source)
+        }
+    }
+
+    pub fn from_review(r: &ReviewMatch) -> Record {
+        Record {
+            alias: Some(r.alias.clone()),
+            target_dir: Some(r.target_dir.clone()),
+            score: Some(r.score),
+            ..Record::new(RecordStatus::Review, &r.source)
+        }
+    }
+
+    pub fn from_skipped(s: &Skipped) -> Record {
+        Record {
+            reason: Some(s.reason.clone()),
+            ..Record::new(RecordStatus::Skipped, &s.path)
+        }
+    }
+
+    pub fn unmatched(source: &Path) -> Record {
+        Record::new(RecordStatus::Unmatched, source)
+    }
+
+    /// File or directory which couldn't be scanned or planned
+    pub fn from_error(e: &FileError) -> Record {
+        Record {
+            error: Some(format!("{} failed: {}", e.stage, e.message)),
+            ..Record::new(RecordStatus::Failed, &e.path)
+        }
+    }
+}
+
+/// Records for everything in the plan which isn't moved: ambiguous, review,
+/// unmatched, skipped and failed files
+pub fn plan_records(plan: &SortPlan) -> Vec<Record> {
+    plan.multiple_matches.iter().map(Record::from_ambiguous)
+        .chain(plan.review.iter().map(Record::from_review))
+        .chain(plan.unmatched.iter().map(|p| Record::unmatched(p)))
+        .chain(plan.skipped.iter().map(Record::from_skipped))
+        .chain(plan.errors.iter().map(Record::from_error))
+        .collect()
+}
+
+/// How a run went as a whole, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RunOutcome {
+    // Everything matched was moved (or would have been in a dry run)
+    Success,
+    // Some files matched multiple aliases or were left for review
+    SomeAmbiguous,
+    // Some files couldn't be scanned, planned or moved
+    SomeFailed,
+}
+
+/// Counts of a run and all failures
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub moved: usize,
+    // Dry run
+    pub not_moved: usize,
+    pub ambiguous: usize,
+    pub review: usize,
+    pub unmatched: usize,
+    pub skipped: usize,
+    pub errors: Vec<FileError>,
+}
+
+impl Summary {
+    /// Everything but the moves, see `add_outcome`
+    pub fn from_plan(plan: &SortPlan) -> Summary {
+        Summary {
+            ambiguous: plan.multiple_matches.len(),
+            review: plan.review.len(),
+            unmatched: plan.unmatched.len(),
+            skipped: plan.skipped.len(),
+            errors: plan.errors.clone(),
+            ..Summary::default()
+        }
+    }
+
+    pub fn add_outcome(&mut self, m: &PlannedMove, outcome: &Outcome) {
+        match outcome {
+            Outcome::Moved => self.moved += 1,
+            Outcome::NotMoved => self.not_moved += 1,
+            Outcome::Failed(e) => self.errors.push(FileError::new(&m.source, Stage::Execute, e)),
+        }
+    }
+
+    pub fn outcome(&self) -> RunOutcome {
+        if !self.errors.is_empty() {
+            RunOutcome::SomeFailed
+        } else if self.ambiguous > 0 || self.review > 0 {
+            RunOutcome::SomeAmbiguous
+        } else {
+            RunOutcome::Success
+        }
+    }
+}
+
+const CSV_HEADER: &str = "version,status,source,alias,target_dir,destination,action,candidates,score,reason,error";
+
+// Quoted if needed, names which are not valid UTF-8 are written lossily
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Serialized name of a unit enum variant: RecordStatus::DryRun -> "dry_run"
+fn variant_name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default()
+}
+
+fn csv_line(r: &Record) -> String {
+    let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+
+    [
+        r.version.to_string(),
+        variant_name(&r.status),
+        r.source.to_string_lossy().into_owned(),
+        r.alias.clone().unwrap_or_default(),
+        path(&r.target_dir),
+        path(&r.destination),
+        r.action.as_ref().map(variant_name).unwrap_or_default(),
+        r.candidates.join(";"),
+        r.score.map(|s| s.to_string()).unwrap_or_default(),
+        r.reason.as_ref().map(variant_name).unwrap_or_default(),
+        r.error.clone().unwrap_or_default(),
+    ]
+        .iter()
+        .map(|f| csv_field(f))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    version: u32,
+    records: &'a [Record],
+}
+
+/// Writes records in `OutputFormat`. NDJSON and CSV records are written
+/// as they come, JSON when finished.
+#[derive(Debug)]
+pub struct ReportWriter<W: Write> {
+    format: OutputFormat,
+    out: W,
+    // JSON records waiting for `finish`
+    records: Vec<Record>,
+    header_written: bool,
+}
+
+impl<W: Write> ReportWriter<W> {
+    pub fn new(format: OutputFormat, out: W) -> ReportWriter<W> {
+        ReportWriter {
+            format,
+            out,
+            records: Vec::new(),
+            header_written: false,
+        }
+    }
+
+    pub fn write(&mut self, record: Record) -> Result<(), Error> {
+        match self.format {
+            OutputFormat::Json => self.records.push(record),
+            OutputFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, &record)?;
+                writeln!(self.out)?;
+            }
+            OutputFormat::Csv => {
+                if !self.header_written {
+                    writeln!(self.out, "{}", CSV_HEADER)?;
+                    self.header_written = true;
+                }
+
+                writeln!(self.out, "{}", csv_line(&record))?;
+            }
+        }
+
+        self.out.flush()
+    }
+
+    /// Write the JSON document, or the CSV header if there were no records
+    pub fn finish(mut self) -> Result<(), Error> {
+        match self.format {
+            OutputFormat::Json => {
+                let report = JsonReport {
+                    version: REPORT_VERSION,
+                    records: &self.records,
+                };
+
+                serde_json::to_writer_pretty(&mut self.out, &report)?;
+                writeln!(self.out)?;
+            }
+            OutputFormat::Csv if !self.header_written => writeln!(self.out, "{}", CSV_HEADER)?,
+            _ => {}
+        }
+
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(format: OutputFormat, records: Vec<Record>) -> String {
+        let mut out = Vec::new();
+        let mut writer = ReportWriter::new(format, &mut out);
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[cfg(unix)]
+    fn invalid_unicode_path() -> PathBuf {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(b"src/Foo \xff.mp3"))
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        let ambiguous = AmbiguousMatch {
+            source: PathBuf::from("src/Foo, Bar \"live\".mp3"),
+            aliases: vec!["Foo".to_string(), "Bar".to_string()],
+        };
+        let error = FileError::new(Path::new("src/a.mp3"), Stage::Plan, &Error::other("line\nbreak"));
+
+        let csv = written(OutputFormat::Csv, vec![Record::from_ambiguous(&ambiguous), Record::from_error(&error)]);
+
+        assert_eq!(csv, format!(
+            "{}\n{}\n{}\n",
+            CSV_HEADER,
+            "1,ambiguous,\"src/Foo, Bar \"\"live\"\".mp3\",,,,,Foo;Bar,,,",
+            "1,failed,src/a.mp3,,,,,,,,\"planning failed: line\nbreak\"",
+        ));
+    }
+
+    #[test]
+    fn csv_header_is_written_without_records() {
+        assert_eq!(written(OutputFormat::Csv, vec![]), format!("{}\n", CSV_HEADER));
+        assert_eq!(written(OutputFormat::Ndjson, vec![]), "");
+    }
+
+    #[test]
+    fn json_and_ndjson_records_round_trip() {
+        let review = ReviewMatch {
+            source: PathBuf::from("src/Metalica.mp3"),
+            alias: "Metallica".to_string(),
+            target_dir: PathBuf::from("dst/Metallica"),
+            score: 88,
+        };
+        let skipped = Skipped {
+            path: PathBuf::from("src/loop"),
+            reason: SkipReason::SymlinkLoop,
+        };
+        let records = vec![Record::from_review(&review), Record::from_skipped(&skipped), Record::unmatched(Path::new("src/x"))];
+
+        let ndjson = written(OutputFormat::Ndjson, records.clone());
+        let lines: Vec<Record> = ndjson.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines, records);
+        assert!(ndjson.contains("\"status\":\"review\""));
+        assert!(ndjson.contains("\"reason\":\"symlink_loop\""));
+
+        let json: serde_json::Value = serde_json::from_str(&written(OutputFormat::Json, records.clone())).unwrap();
+        assert_eq!(json["version"], REPORT_VERSION);
+        let parsed: Vec<Record> = serde_json::from_value(json["records"].clone()).unwrap();
+        assert_eq!(parsed, records);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_unicode_paths_are_bytes_in_json_and_lossy_in_csv() {
+        let record = Record::unmatched(&invalid_unicode_path());
+
+        let ndjson = written(OutputFormat::Ndjson, vec![record.clone()]);
+        assert!(ndjson.contains("\"source\":{\"bytes\":["));
+        let parsed: Record = serde_json::from_str(ndjson.trim_end()).unwrap();
+        assert_eq!(parsed.source, record.source);
+
+        let csv = written(OutputFormat::Csv, vec![record]);
+        assert_eq!(csv.lines().nth(1), Some("1,unmatched,src/Foo \u{fffd}.mp3,,,,,,,,"));
+    }
+
+    #[test]
+    fn worst_outcome_wins() {
+        let mut summary = Summary::default();
+        assert_eq!(summary.outcome(), RunOutcome::Success);
+
+        summary.review = 1;
+        assert_eq!(summary.outcome(), RunOutcome::SomeAmbiguous);
+
+        summary.errors.push(FileError::new(Path::new("src/a.mp3"), Stage::Scan, &Error::other("failed")));
+        assert_eq!(summary.outcome(), RunOutcome::SomeFailed);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FileError, Stage};
+use crate::filter::FileFilter;
+
+/// How source directories are traversed
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    // Some(0) scans only the source directories themselves, None is unlimited
+    pub max_depth: Option<usize>,
+    // Do not descend into directories on other file systems (mount points)
+    pub one_file_system: bool,
+    // Collect directories as candidates to be sorted as one unit
+    pub directories: bool,
+    // Files and directories left alone
+    pub filter: FileFilter,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            max_depth: Some(0),
+            one_file_system: false,
+            directories: false,
+            filter: FileFilter::default(),
+        }
+    }
+}
+
+/// Why a path was left out of sorting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    // Symlink points back to one of its parent directories
+    SymlinkLoop,
+    // Directory was already scanned through another path
+    AlreadyScanned,
+    // Mount point, see `ScanOptions::one_file_system`
+    OtherFileSystem,
+    // Target directory inside a source directory
+    Target,
+    // Destination exists, see `CollisionPolicy`
+    DestinationExists,
+    // Destination modified at the same time or later than the source
+    DestinationNotOlder,
+    // Destination at least as large as the source
+    DestinationNotSmaller,
+    // Identical file already in the target directory
+    Identical,
+    // Name can't be made to fit the target file system's limits
+    NameTooLong,
+    // Name is not valid UTF-8 and didn't match any alias when decoded lossily
+    InvalidUnicode,
+    // Partial download, see `BusyOptions::partial_patterns`
+    PartialDownload,
+    // Held open for writing by some process
+    OpenForWriting,
+    // Modified too recently, see `BusyOptions::min_age`
+    RecentlyModified,
+    // Size or modification time changed during `BusyOptions::stable_check`
+    Changing,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::SymlinkLoop => write!(f, "symlink loop"),
+            SkipReason::AlreadyScanned => write!(f, "already scanned"),
+            SkipReason::OtherFileSystem => write!(f, "on another file system"),
+            SkipReason::Target => write!(f, "target directory"),
+            SkipReason::DestinationExists => write!(f, "destination exists"),
+            SkipReason::DestinationNotOlder => write!(f, "destination is not older"),
+            SkipReason::DestinationNotSmaller => write!(f, "destination is not smaller"),
+            SkipReason::Identical => write!(f, "identical file exists"),
+            SkipReason::NameTooLong => write!(f, "name or path too long for the target"),
+            SkipReason::InvalidUnicode => write!(f, "name is not valid UTF-8"),
+            SkipReason::PartialDownload => write!(f, "partial download"),
+            SkipReason::OpenForWriting => write!(f, "open for writing"),
+            SkipReason::RecentlyModified => write!(f, "modified too recently"),
+            SkipReason::Changing => write!(f, "still changing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Skipped {
+    #[serde(with = "crate::os_name::path")]
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+/// Files found in source directories
+#[derive(Debug, Clone, Default)]
+pub struct Scan {
+    // Sorted by path
+    pub files: Vec<PathBuf>,
+    // Sorted by path, see `ScanOptions::directories`
+    pub directories: Vec<PathBuf>,
+    pub skipped: Vec<Skipped>,
+    // Unreadable directories, the rest are still scanned
+    pub errors: Vec<FileError>,
+}
+
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    // Not supported, every directory is considered to be on the same file system
+    None
+}
+
+struct Walker<'a> {
+    options: &'a ScanOptions,
+    // Canonical paths of directories scanned or collected so far
+    visited: HashSet<PathBuf>,
+    // Canonical paths never scanned nor collected
+    exclude: HashSet<PathBuf>,
+    // Source directory being scanned, filters see paths relative to it
+    root: PathBuf,
+    scan: Scan,
+}
+
+impl Walker<'_> {
+    fn walk(
+        &mut self,
+        dir: &Path,
+        depth: usize,
+        root_device: Option<u64>,
+        ancestors: &mut Vec<PathBuf>,
+    ) {
+        let mut entries: Vec<PathBuf> = Vec::new();
+
+        let read = match fs::read_dir(dir) {
+            Ok(r) => r,
+            Err(e) => return self.fail(dir, &e),
+        };
+
+        for entry in read {
+            match entry {
+                Ok(e) => entries.push(e.path()),
+                Err(e) => self.fail(dir, &e),
+            }
+        }
+
+        entries.sort();
+
+        for path in entries {
+            // Follows symlinks
+            let is_dir = path.is_dir();
+
+            if !self.accepts(&path, is_dir) {
+                continue;
+            }
+
+            if !is_dir {
+                self.scan.files.push(path);
+                continue;
+            }
+
+            let canonical = match fs::canonicalize(&path) {
+                Ok(c) => c,
+                Err(e) => {
+                    self.fail(&path, &e);
+                    continue;
+                }
+            };
+
+            if self.exclude.contains(&canonical) {
+                self.skip(path, SkipReason::Target);
+                continue;
+            }
+
+            let descend = self.options.max_depth.is_none_or(|max| depth < max);
+
+            if !descend && !self.options.directories {
+                continue;
+            }
+
+            if ancestors.contains(&canonical) {
+                self.skip(path, SkipReason::SymlinkLoop);
+                continue;
+            }
+
+            if self.visited.contains(&canonical) {
+                self.skip(path, SkipReason::AlreadyScanned);
+                continue;
+            }
+
+            if self.options.one_file_system && device_id(&path) != root_device {
+                self.skip(path, SkipReason::OtherFileSystem);
+                continue;
+            }
+
+            self.visited.insert(canonical.clone());
+
+            // Only after the checks above, skipped directories are not moved either
+            if self.options.directories {
+                self.scan.directories.push(path.clone());
+            }
+
+            if !descend {
+                continue;
+            }
+
+            ancestors.push(canonical);
+            self.walk(&path, depth + 1, root_device, ancestors);
+            ancestors.pop();
+        }
+    }
+
+    fn accepts(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        self.options.filter.accepts(relative, is_dir)
+    }
+
+    fn fail(&mut self, path: &Path, error: &Error) {
+        self.scan.errors.push(FileError::new(path, Stage::Scan, error));
+    }
+
+    fn skip(&mut self, path: PathBuf, reason: SkipReason) {
+        self.scan.skipped.push(Skipped { path, reason });
+    }
+}
+
+/// Find files to be sorted from `sources`, directories in `exclude` are
+/// skipped. Directories which can't be read are listed in `Scan::errors`.
+pub fn scan_sources(
+    sources: &[PathBuf],
+    options: &ScanOptions,
+    exclude: &[PathBuf],
+) -> Result<Scan, Error> {
+    if sources.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "no sources"));
+    }
+
+    let mut walker = Walker {
+        options,
+        visited: HashSet::new(),
+        exclude: exclude.iter()
+            .filter_map(|p| fs::canonicalize(p).ok())
+            .collect(),
+        root: PathBuf::new(),
+        scan: Scan::default(),
+    };
+
+    for dir in sources {
+        if !dir.is_dir() {
+            continue;
+        }
+
+        let canonical = match fs::canonicalize(dir) {
+            Ok(c) => c,
+            Err(e) => {
+                walker.fail(dir, &e);
+                continue;
+            }
+        };
+
+        if walker.exclude.contains(&canonical) {
+            walker.skip(dir.clone(), SkipReason::Target);
+            continue;
+        }
+
+        if walker.visited.contains(&canonical) {
+            walker.skip(dir.clone(), SkipReason::AlreadyScanned);
+            continue;
+        }
+
+        walker.visited.insert(canonical.clone());
+        walker.root = dir.clone();
+        walker.walk(dir, 0, device_id(dir), &mut vec![canonical]);
+    }
+
+    let mut scan = walker.scan;
+    scan.files.sort();
+    scan.files.dedup();
+    scan.directories.sort();
+    scan.directories.dedup();
+
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testdir::temp_dir;
+
+    fn options(max_depth: Option<usize>, directories: bool) -> ScanOptions {
+        ScanOptions {
+            max_depth,
+            directories,
+            ..ScanOptions::default()
+        }
+    }
+
+    fn skipped(scan: &Scan) -> Vec<(PathBuf, SkipReason)> {
+        scan.skipped.iter().map(|s| (s.path.clone(), s.reason.clone())).collect()
+    }
+
+    #[test]
+    fn max_depth_limits_files_and_directories() {
+        let src = temp_dir("scan-depth");
+        fs::create_dir_all(src.join("sub").join("deeper")).unwrap();
+        fs::write(src.join("a.txt"), "").unwrap();
+        fs::write(src.join("sub").join("b.txt"), "").unwrap();
+        fs::write(src.join("sub").join("deeper").join("c.txt"), "").unwrap();
+        let sources = [src.clone()];
+
+        let scan = scan_sources(&sources, &options(Some(0), true), &[]).unwrap();
+        assert_eq!(scan.files, vec![src.join("a.txt")]);
+        assert_eq!(scan.directories, vec![src.join("sub")]);
+
+        let scan = scan_sources(&sources, &options(Some(1), true), &[]).unwrap();
+        assert_eq!(scan.files, vec![src.join("a.txt"), src.join("sub").join("b.txt")]);
+        assert_eq!(scan.directories, vec![src.join("sub"), src.join("sub").join("deeper")]);
+
+        let scan = scan_sources(&sources, &options(None, false), &[]).unwrap();
+        assert_eq!(scan.files.len(), 3);
+        assert!(scan.directories.is_empty());
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop_is_skipped_and_not_collected() {
+        let src = temp_dir("scan-loop");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub").join("b.txt"), "").unwrap();
+        std::os::unix::fs::symlink("..", src.join("sub").join("loop")).unwrap();
+
+        let scan = scan_sources(std::slice::from_ref(&src), &options(None, true), &[]).unwrap();
+        assert_eq!(skipped(&scan), vec![(src.join("sub").join("loop"), SkipReason::SymlinkLoop)]);
+        assert_eq!(scan.directories, vec![src.join("sub")]);
+        assert_eq!(scan.files, vec![src.join("sub").join("b.txt")]);
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_is_scanned_once() {
+        let root = temp_dir("scan-once");
+        let (src, other) = (root.join("src"), root.join("other"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("b.txt"), "").unwrap();
+        std::os::unix::fs::symlink(&other, src.join("first")).unwrap();
+        std::os::unix::fs::symlink(&other, src.join("second")).unwrap();
+
+        // Also given as a source of its own
+        let scan = scan_sources(&[src.clone(), other.clone()], &options(None, true), &[]).unwrap();
+        assert_eq!(skipped(&scan), vec![
+            (src.join("second"), SkipReason::AlreadyScanned),
+            (other.clone(), SkipReason::AlreadyScanned),
+        ]);
+        assert_eq!(scan.directories, vec![src.join("first")]);
+        assert_eq!(scan.files, vec![src.join("first").join("b.txt")]);
+
+        // Collected without descending
+        let scan = scan_sources(std::slice::from_ref(&src), &options(Some(0), true), &[]).unwrap();
+        assert_eq!(skipped(&scan), vec![(src.join("second"), SkipReason::AlreadyScanned)]);
+        assert_eq!(scan.directories, vec![src.join("first")]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn target_directories_are_skipped() {
+        let src = temp_dir("scan-target");
+        fs::create_dir_all(src.join("Foo")).unwrap();
+        fs::write(src.join("Foo").join("foo.txt"), "").unwrap();
+
+        let scan = scan_sources(std::slice::from_ref(&src), &options(None, true), &[src.join("Foo")]).unwrap();
+        assert_eq!(skipped(&scan), vec![(src.join("Foo"), SkipReason::Target)]);
+        assert!(scan.files.is_empty() && scan.directories.is_empty());
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn one_file_system_skips_mount_points() {
+        // Needs a directory on another file system than the temporary directory
+        let shm = Path::new("/dev/shm");
+        let src = temp_dir("scan-one-fs");
+
+        if !shm.is_dir() || device_id(shm) == device_id(&src) {
+            fs::remove_dir_all(&src).unwrap();
+            return;
+        }
+
+        let other = shm.join(src.file_name().unwrap());
+        let _ = fs::remove_dir_all(&other);
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("b.txt"), "").unwrap();
+        std::os::unix::fs::symlink(&other, src.join("mnt")).unwrap();
+
+        let one_fs = ScanOptions {
+            one_file_system: true,
+            ..options(None, true)
+        };
+        let scan = scan_sources(std::slice::from_ref(&src), &one_fs, &[]).unwrap();
+        assert_eq!(skipped(&scan), vec![(src.join("mnt"), SkipReason::OtherFileSystem)]);
+        assert!(scan.files.is_empty() && scan.directories.is_empty());
+
+        let scan = scan_sources(std::slice::from_ref(&src), &options(None, true), &[]).unwrap();
+        assert_eq!(scan.files, vec![src.join("mnt").join("b.txt")]);
+
+        fs::remove_dir_all(&other).unwrap();
+        fs::remove_dir_all(&src).unwrap();
+    }
+}
@@ -0,0 +1,13 @@
+// Scratch directories for unit tests and the tests in tests/
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Empty directory for one test, removed first if left over from an earlier
+/// run. `name` has to be unique among all tests, they run in parallel.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lajittelia-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,236 @@
+use std::fs::{self, File, FileTimes};
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of file contents as a hex string
+pub fn checksum(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+
+    loop {
+        let n = file.read(&mut buf)?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Are `a` and `b` regular files with the same size and SHA-256 checksum
+pub fn identical(a: &Path, b: &Path) -> Result<bool, Error> {
+    let (meta_a, meta_b) = (a.symlink_metadata()?, b.symlink_metadata()?);
+
+    if !meta_a.is_file() || !meta_b.is_file() || meta_a.len() != meta_b.len() {
+        return Ok(false);
+    }
+
+    Ok(checksum(a)? == checksum(b)?)
+}
+
+/// Move file or directory. Tries `fs::rename` first and if source and
+/// destination are on different file systems, falls back to copying,
+/// verifying the copy and then removing the source.
+pub fn move_path(source: &Path, destination: &Path, copy_fallback: bool) -> Result<(), Error> {
+    match fs::rename(source, destination) {
+        Ok(()) => Ok(()),
+        Err(e) if copy_fallback && e.kind() == ErrorKind::CrossesDevices => {
+            copy_verify_delete(source, destination)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy `source` to `destination` preserving mtime, permissions and xattrs,
+/// verify the copy by size and checksum, and remove `source` when the copy
+/// is verified. Partial copy is removed on failure.
+pub fn copy_verify_delete(source: &Path, destination: &Path) -> Result<(), Error> {
+    copy_path(source, destination)?;
+    remove_path(source)
+}
+
+/// Copy file or directory like `copy_verify_delete`, but keep `source`
+pub fn copy_path(source: &Path, destination: &Path) -> Result<(), Error> {
+    if destination.symlink_metadata().is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("destination exists: {}", destination.display()),
+        ));
+    }
+
+    let copied = copy_tree(source, destination)
+        .and_then(|_| verify_tree(source, destination));
+
+    if let Err(e) = copied {
+        // Clean up partial copy, the original error is the interesting one
+        let _ = remove_path(destination);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Hard link `source` to `destination`, directories can't be linked
+pub fn link_path(source: &Path, destination: &Path) -> Result<(), Error> {
+    if source.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("can't hard link a directory: {}", source.display()),
+        ));
+    }
+
+    fs::hard_link(source, destination)
+}
+
+pub(crate) fn remove_path(path: &Path) -> Result<(), Error> {
+    let meta = path.symlink_metadata()?;
+
+    if meta.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn copy_tree(source: &Path, destination: &Path) -> Result<(), Error> {
+    let meta = source.symlink_metadata()?;
+
+    if meta.file_type().is_symlink() {
+        return copy_symlink(source, destination);
+    }
+
+    if meta.is_dir() {
+        fs::create_dir(destination)?;
+
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+    } else {
+        // Also copies permissions
+        fs::copy(source, destination)?;
+    }
+
+    copy_xattrs(source, destination)?;
+
+    // Directory contents are copied at this point, so read-only permissions
+    // and mtime can be set
+    fs::set_permissions(destination, meta.permissions())?;
+
+    let mut times = FileTimes::new().set_modified(meta.modified()?);
+
+    if let Ok(accessed) = meta.accessed() {
+        times = times.set_accessed(accessed);
+    }
+
+    set_times(destination, times, meta.is_dir())
+}
+
+#[cfg(unix)]
+fn set_times(path: &Path, times: FileTimes, _is_dir: bool) -> Result<(), Error> {
+    File::open(path)?.set_times(times)
+}
+
+#[cfg(not(unix))]
+fn set_times(path: &Path, times: FileTimes, is_dir: bool) -> Result<(), Error> {
+    if is_dir {
+        // Directories can't be opened with `File` here
+        return Ok(());
+    }
+
+    File::options().write(true).open(path)?.set_times(times)
+}
+
+#[cfg(unix)]
+fn copy_symlink(source: &Path, destination: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(fs::read_link(source)?, destination)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(source: &Path, destination: &Path) -> Result<(), Error> {
+    // Copy what the link points to
+    fs::copy(source, destination).map(|_| ())
+}
+
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, destination: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let unsupported = |e: &Error| {
+        e.kind() == ErrorKind::Unsupported || e.raw_os_error() == Some(libc::ENOTSUP)
+    };
+
+    let names: Vec<_> = match xattr::list(source) {
+        Ok(names) => names.collect(),
+        Err(e) if unsupported(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    // Setting xattrs needs write permission, final permissions are set
+    // by the caller
+    let mode = destination.metadata()?.permissions().mode();
+
+    if mode & 0o200 == 0 {
+        fs::set_permissions(destination, fs::Permissions::from_mode(mode | 0o200))?;
+    }
+
+    for name in names {
+        if let Some(value) = xattr::get(source, &name)? {
+            match xattr::set(destination, &name, &value) {
+                Ok(()) => {}
+                // Target file system (example: NFS) might not support xattrs
+                Err(e) if unsupported(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_xattrs(_source: &Path, _destination: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+// Compare sizes and checksums of copied files
+fn verify_tree(source: &Path, destination: &Path) -> Result<(), Error> {
+    let meta = source.symlink_metadata()?;
+
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    if meta.is_dir() {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            verify_tree(&entry.path(), &destination.join(entry.file_name()))?;
+        }
+
+        return Ok(());
+    }
+
+    let copied = destination.metadata()?;
+
+    if copied.len() != meta.len() || checksum(source)? != checksum(destination)? {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("copy verification failed: {}", destination.display()),
+        ));
+    }
+
+    Ok(())
+}
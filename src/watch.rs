@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+use notify::event::{CreateKind, EventKind, ModifyKind, RemoveKind};
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::alias::{AliasIndex, ALIAS_FILE};
+use crate::busy::{self, all_paths};
+use crate::filter::FileFilter;
+use crate::matcher::Matcher;
+use crate::plan::{PlanOptions, SortPlan};
+use crate::scan::scan_sources;
+
+/// Timing of watch mode
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions {
+    // Time without events before a file is looked at
+    pub debounce: Duration,
+    // Size and modification time must stay the same this long
+    pub stable_for: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            debounce: Duration::from_secs(2),
+            stable_for: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Reported by `watch`
+#[derive(Debug)]
+pub enum WatchEvent<'a> {
+    // Initial scan done, waiting for events
+    Started,
+    // Target directories changed and aliases were read again
+    Reloaded(&'a AliasIndex),
+    // Reading aliases failed, previous aliases are still used
+    ReloadFailed(Error),
+    // Plan for settled files, to be executed by the caller
+    Planned(&'a SortPlan),
+}
+
+// Size and newest modification time, of everything inside for directories
+// as writes inside a directory cause no events for the directory itself.
+// None if the path no longer exists.
+fn snapshot(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    path.symlink_metadata().ok()?;
+    Some(busy::snapshot(&all_paths(path)))
+}
+
+#[derive(Debug)]
+struct Pending {
+    last_event: Instant,
+    // Snapshot and when it was taken
+    seen: Option<((u64, Option<SystemTime>), Instant)>,
+}
+
+/// Paths seen in file system events, waiting for writing to finish
+#[derive(Debug, Default)]
+pub struct Settler {
+    options: WatchOptions,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+impl Settler {
+    pub fn new(options: WatchOptions) -> Settler {
+        Settler {
+            options,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Something happened to `path`, restarts its debounce time
+    pub fn touch(&mut self, path: PathBuf) {
+        let now = Instant::now();
+
+        self.pending.entry(path)
+            .and_modify(|p| p.last_event = now)
+            .or_insert(Pending {
+                last_event: now,
+                seen: None,
+            });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Remove and return paths which have had no events for the debounce
+    /// time and whose size and modification time have stayed the same.
+    /// Paths which no longer exist are forgotten.
+    pub fn settled(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let options = self.options;
+        let mut ready = Vec::new();
+
+        self.pending.retain(|path, p| {
+            if now.duration_since(p.last_event) < options.debounce {
+                return true;
+            }
+
+            let current = match snapshot(path) {
+                Some(s) => s,
+                None => return false,
+            };
+
+            match p.seen {
+                Some((previous, since)) if previous == current => {
+                    if now.duration_since(since) >= options.stable_for {
+                        ready.push(path.clone());
+                        return false;
+                    }
+                }
+                _ => p.seen = Some((current, now)),
+            }
+
+            true
+        });
+
+        ready.sort();
+        ready
+    }
+}
+
+// Does the event change the set of target directories or their rules
+fn changes_targets(event: &Event) -> bool {
+    let rules_file = event.paths.iter()
+        .any(|p| p.file_name().is_some_and(|n| n == ALIAS_FILE));
+
+    match event.kind {
+        EventKind::Create(CreateKind::Folder) | EventKind::Remove(RemoveKind::Folder) => true,
+        // Renamed directories, or directories moved in or out
+        EventKind::Modify(ModifyKind::Name(_)) => rules_file || event.paths.iter().any(|p| p.is_dir()),
+        EventKind::Remove(_) => rules_file,
+        EventKind::Create(_) | EventKind::Modify(_) => rules_file,
+        _ => false,
+    }
+}
+
+/// Watch `sources` and sort files as they arrive. Files already in the
+/// sources are handled first. `load` reads the alias index of `target`, it
+/// is called again when directories are added to or removed from `target`
+/// and the sources are then looked at again.
+/// Runs until watching fails.
+pub fn watch<L, R>(
+    target: &Path,
+    sources: &[PathBuf],
+    plan_options: &PlanOptions,
+    options: &WatchOptions,
+    mut load: L,
+    mut report: R,
+) -> Result<(), Error>
+    where L: FnMut() -> Result<(AliasIndex, Matcher), Error>,
+          R: FnMut(WatchEvent) {
+    let (mut index, mut matcher) = load()?;
+
+    // Settled files (and everything inside settled directories) have
+    // already stayed the same for `stable_for`
+    let mut plan_options = plan_options.clone();
+    plan_options.busy.stable_check = None;
+    let plan_options = &plan_options;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(Error::other)?;
+
+    let source_mode = match plan_options.scan.max_depth {
+        Some(0) => RecursiveMode::NonRecursive,
+        _ => RecursiveMode::Recursive,
+    };
+
+    for source in sources {
+        watcher.watch(source, source_mode).map_err(Error::other)?;
+    }
+
+    watcher.watch(target, RecursiveMode::Recursive).map_err(Error::other)?;
+
+    let mut settler = Settler::new(*options);
+    let tick = Duration::from_millis(500);
+
+    touch_existing(&mut settler, sources, plan_options, &index)?;
+
+    report(WatchEvent::Started);
+
+    loop {
+        let mut reload = false;
+
+        match rx.recv_timeout(tick) {
+            Ok(Ok(event)) => {
+                if event.paths.iter().any(|p| p.starts_with(target)) {
+                    reload = changes_targets(&event);
+                } else if !matches!(event.kind, EventKind::Access(_) | EventKind::Remove(_)) {
+                    for path in event.paths {
+                        if within_depth(&path, sources, plan_options.scan.max_depth)
+                            && accepted(&path, sources, &plan_options.scan.filter) {
+                            settler.touch(path);
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => return Err(Error::other(e)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        if reload {
+            match load() {
+                Ok((i, m)) => {
+                    index = i;
+                    matcher = m;
+                    report(WatchEvent::Reloaded(&index));
+
+                    // Files left unmatched may match the new directories
+                    touch_existing(&mut settler, sources, plan_options, &index)?;
+                }
+                Err(e) => report(WatchEvent::ReloadFailed(e)),
+            }
+        }
+
+        if settler.is_empty() {
+            continue;
+        }
+
+        let mut settled = Vec::new();
+
+        for path in settler.settled() {
+            // Directory moved in as a whole: its files are sorted one by one,
+            // as far as the depth limit of the sources allows
+            if path.is_dir() && !plan_options.scan.directories {
+                let mut scan = plan_options.scan.clone();
+                scan.max_depth = match (scan.max_depth, source_depth(&path, sources)) {
+                    (Some(max), Some(depth)) if depth < max => Some(max - depth - 1),
+                    (Some(_), _) => continue,
+                    (None, _) => None,
+                };
+
+                for file in scan_sources(&[path], &scan, &index.directories())?.files {
+                    // Filters see paths relative to the source, not the directory
+                    if within_depth(&file, sources, plan_options.scan.max_depth)
+                        && accepted(&file, sources, &scan.filter) {
+                        settler.touch(file);
+                    }
+                }
+
+                continue;
+            }
+
+            settled.push(path);
+        }
+
+        if !settled.is_empty() {
+            let plan = SortPlan::build_paths(&index, &matcher, &settled, plan_options)?;
+            report(WatchEvent::Planned(&plan));
+        }
+    }
+}
+
+// Files already in the sources, and directories with
+// `ScanOptions::directories`, have to settle as well
+fn touch_existing(settler: &mut Settler, sources: &[PathBuf], options: &PlanOptions, index: &AliasIndex) -> Result<(), Error> {
+    let scan = scan_sources(sources, &options.scan, &index.directories())?;
+
+    for path in scan.directories.into_iter().chain(scan.files) {
+        settler.touch(path);
+    }
+
+    Ok(())
+}
+
+// Directories between `path` and the first of `sources` it is in
+fn source_depth(path: &Path, sources: &[PathBuf]) -> Option<usize> {
+    sources.iter()
+        .find_map(|source| path.strip_prefix(source).ok())
+        .map(|relative| relative.components().count().saturating_sub(1))
+}
+
+// Is `path` at most `max_depth` directories below one of `sources`
+fn within_depth(path: &Path, sources: &[PathBuf], max_depth: Option<usize>) -> bool {
+    sources.iter().any(|source| match source_depth(path, std::slice::from_ref(source)) {
+        Some(depth) => max_depth.is_none_or(|max| depth <= max),
+        None => false,
+    })
+}
+
+// Does `filter` accept `path` and the directories between it and its source
+fn accepted(path: &Path, sources: &[PathBuf], filter: &FileFilter) -> bool {
+    let relative = match sources.iter().find_map(|s| path.strip_prefix(s).ok()) {
+        Some(r) => r,
+        None => return true,
+    };
+
+    let parents_accepted = relative.ancestors()
+        .skip(1)
+        .filter(|p| !p.as_os_str().is_empty())
+        .all(|p| filter.accepts(p, true));
+
+    parents_accepted && filter.accepts(relative, path.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::thread::sleep;
+
+    use crate::testdir::temp_dir;
+
+    const DEBOUNCE: Duration = Duration::from_millis(100);
+    const STABLE_FOR: Duration = Duration::from_millis(100);
+
+    fn settler() -> Settler {
+        Settler::new(WatchOptions {
+            debounce: DEBOUNCE,
+            stable_for: STABLE_FOR,
+        })
+    }
+
+    #[test]
+    fn unchanged_file_settles_after_debounce_and_stable_time() {
+        let dir = temp_dir("watch-settle");
+        let file = dir.join("song.mp3");
+        fs::write(&file, "a").unwrap();
+
+        let mut settler = settler();
+        settler.touch(file.clone());
+        assert!(settler.settled().is_empty());
+
+        // First snapshot after the debounce time, settled after stable time
+        sleep(DEBOUNCE);
+        assert!(settler.settled().is_empty());
+        sleep(STABLE_FOR);
+        assert_eq!(settler.settled(), vec![file]);
+        assert!(settler.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn events_and_writes_restart_waiting() {
+        let dir = temp_dir("watch-restart");
+        fs::create_dir_all(dir.join("album")).unwrap();
+        fs::write(dir.join("album").join("track1.mp3"), "a").unwrap();
+
+        let mut settler = settler();
+        settler.touch(dir.join("album"));
+        sleep(DEBOUNCE);
+        settler.touch(dir.join("album"));
+        assert!(settler.settled().is_empty());
+
+        sleep(DEBOUNCE);
+        assert!(settler.settled().is_empty());
+
+        // Writes inside a directory change its snapshot
+        fs::write(dir.join("album").join("track2.mp3"), "bb").unwrap();
+        sleep(STABLE_FOR);
+        assert!(settler.settled().is_empty());
+        sleep(STABLE_FOR);
+        assert_eq!(settler.settled(), vec![dir.join("album")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removed_paths_are_forgotten() {
+        let dir = temp_dir("watch-removed");
+
+        let mut settler = settler();
+        settler.touch(dir.join("gone.mp3"));
+        sleep(DEBOUNCE);
+
+        assert!(settler.settled().is_empty());
+        assert!(settler.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn depth_is_counted_from_the_source() {
+        let sources = vec![PathBuf::from("/src")];
+
+        assert_eq!(source_depth(Path::new("/src/a.mp3"), &sources), Some(0));
+        assert_eq!(source_depth(Path::new("/src/album/a.mp3"), &sources), Some(1));
+        assert_eq!(source_depth(Path::new("/other/a.mp3"), &sources), None);
+
+        assert!(within_depth(Path::new("/src/album/a.mp3"), &sources, Some(1)));
+        assert!(!within_depth(Path::new("/src/album/a.mp3"), &sources, Some(0)));
+        assert!(within_depth(Path::new("/src/album/disc/a.mp3"), &sources, None));
+    }
+}
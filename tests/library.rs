@@ -0,0 +1,46 @@
+// Sorting through the library API, the way other programs use it
+
+use std::fs;
+
+use lajittelia::{AliasIndex, Executor, Matcher, Outcome, PlanOptions, SortPlan};
+
+#[path = "../src/testdir.rs"]
+mod testdir;
+
+use testdir::temp_dir;
+
+#[test]
+fn plan_and_execute() {
+    let root = temp_dir("library");
+    let (target, source) = (root.join("sorted"), root.join("not-sorted"));
+
+    fs::create_dir_all(target.join("Foo Bar")).unwrap();
+    fs::create_dir_all(target.join("Baz")).unwrap();
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("Foo.Bar - Live.txt"), "foo").unwrap();
+    fs::write(source.join("something else.txt"), "else").unwrap();
+
+    let index = AliasIndex::from_target(&target).unwrap();
+    let matcher = Matcher::new(&index).unwrap();
+    let plan = SortPlan::build(&index, &matcher, std::slice::from_ref(&source), &PlanOptions::default()).unwrap();
+
+    assert_eq!(plan.moves.len(), 1);
+    assert_eq!(plan.moves[0].source, source.join("Foo.Bar - Live.txt"));
+    assert_eq!(plan.moves[0].destination, target.join("Foo Bar").join("Foo.Bar - Live.txt"));
+    assert_eq!(plan.unmatched, vec![source.join("something else.txt")]);
+
+    // Dry run leaves everything in place
+    let mut outcomes = Vec::new();
+    Executor::new(false).execute(&plan, |_, outcome| outcomes.push(matches!(outcome, Outcome::NotMoved)));
+    assert_eq!(outcomes, vec![true]);
+    assert!(source.join("Foo.Bar - Live.txt").exists());
+
+    let mut outcomes = Vec::new();
+    Executor::new(true).execute(&plan, |_, outcome| outcomes.push(matches!(outcome, Outcome::Moved)));
+    assert_eq!(outcomes, vec![true]);
+    assert!(!source.join("Foo.Bar - Live.txt").exists());
+    assert_eq!(fs::read_to_string(target.join("Foo Bar").join("Foo.Bar - Live.txt")).unwrap(), "foo");
+    assert!(source.join("something else.txt").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}